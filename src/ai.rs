@@ -1,118 +1,229 @@
-use anyhow::Result;
-use async_recursion::async_recursion;
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
 use lazy_static::lazy_static;
-use openai::{
-    chat::{
-        ChatCompletion, ChatCompletionFunctionDefinition, ChatCompletionMessage,
-        ChatCompletionMessageRole,
-    },
-    set_key,
-};
-use serde_json::{json, Value};
-use serenity::{model::prelude::Message, prelude::Context};
+use serde_json::json;
+use serenity::{futures::future::join_all, model::prelude::Message, prelude::Context};
 use std::{
     collections::HashMap,
-    env,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex as AsyncMutex},
+    time::{timeout_at, Instant},
 };
 
-use crate::extensions::MessageExt;
-
-pub static ACTIVE_CONVO: AtomicU64 = AtomicU64::new(0);
+use crate::{
+    attachments, attention,
+    config::{self, config},
+    conversation::{self, Addressing},
+    extensions::MessageExt,
+    memory,
+    mentions::{self, Names},
+    provider::{provider, ChatMessage, ChatRequest, ChatResponse, ContentPart, Role},
+    reply::StreamingReply,
+    storage::storage,
+    summary, tokens,
+    tools::{self, ToolContext},
+    usage::{self, Quota},
+};
 
 lazy_static! {
-    pub static ref USERS: HashMap<&'static str, usize> = {
-        let mut hashmap = HashMap::new();
-        hashmap.insert("JonJo", 72878083471847424);
-        hashmap.insert("Kay", 143276836481269760);
-        hashmap.insert("Andrew", 142518394036420608);
-        hashmap.insert("Derek", 142889606260457472);
-        hashmap.insert("Chris", 296787008184123393);
-        hashmap.insert("Sidd", 143979701960966144);
-        hashmap.insert("Kebin", 215221184009076736);
-        hashmap.insert("Yves", 279027216459759626);
-        hashmap.insert("Daniel", 124634935872061444);
-        hashmap.insert("Alex", 144979023146123264);
-        hashmap.insert("Evan", 217010389152563202);
-        hashmap.insert("Grant", 388148320897335310);
-        hashmap.insert("Kevin", 172953593874350081);
-        hashmap
-    };
+    static ref HISTORY_LOCK: Mutex<()> = Mutex::new(());
+    // One response at a time per channel, so that nothing gets saved between
+    // a message with tool calls and the results of those calls.
+    static ref CHANNEL_LOCKS: Mutex<HashMap<u64, Arc<AsyncMutex<()>>>> =
+        Mutex::new(HashMap::new());
+    // Messages evicted from each channel's history, waiting to be summarized
+    // and remembered.
+    static ref UPKEEP: Mutex<HashMap<u64, mpsc::UnboundedSender<Vec<ChatMessage>>>> =
+        Mutex::new(HashMap::new());
 }
 
-pub fn auth() {
-    set_key(env::var("OPENAI_KEY").unwrap());
+fn channel_lock(channel_id: u64) -> Arc<AsyncMutex<()>> {
+    CHANNEL_LOCKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .entry(channel_id)
+        .or_default()
+        .clone()
 }
 
 fn astro_identity() -> String {
-    std::fs::read_to_string("identity.txt").unwrap()
+    config::identity().to_string()
+}
+
+// The channel's history, followed by tool results which haven't been saved
+// yet.
+fn previous_messages(channel_id: u64, pending: &[ChatMessage]) -> Result<Vec<ChatMessage>> {
+    let messages = storage()
+        .messages(channel_id)?
+        .into_iter()
+        .filter(|message| !message.is_legacy())
+        .chain(pending.iter().cloned());
+    Ok(answered_tool_calls(messages))
 }
 
-fn previous_messages() -> Vec<ChatCompletionMessage> {
-    std::fs::read_to_string("messages.json")
-        .ok()
-        .and_then(|messages| serde_json::from_str(&messages).ok())
-        .unwrap_or_default()
+// Drops tool calls which aren't directly followed by all of their results,
+// and results which don't directly follow their call. The API rejects either,
+// so a response which broke off halfway would otherwise fail every request in
+// the channel from then on.
+fn answered_tool_calls(messages: impl Iterator<Item = ChatMessage>) -> Vec<ChatMessage> {
+    let mut messages = messages.peekable();
+    let mut kept = Vec::new();
+    while let Some(message) = messages.next() {
+        if message.role == Role::Tool {
+            continue;
+        }
+        if message.tool_calls().is_empty() {
+            kept.push(message);
+            continue;
+        }
+
+        let mut results = Vec::new();
+        while let Some(result) = messages.next_if(|message| message.role == Role::Tool) {
+            results.push(result);
+        }
+        let answered = message.tool_calls().iter().all(|tool_call| {
+            results
+                .iter()
+                .any(|result| result.tool_call_id.as_ref() == Some(&tool_call.id))
+        });
+        if answered {
+            kept.push(message);
+            kept.extend(results);
+        } else {
+            println!("Leaving tool calls without results out of the history");
+        }
+    }
+    kept
 }
 
-fn append_message(message: &ChatCompletionMessage) {
-    let mut previous_messages = previous_messages();
-    previous_messages.push(message.clone());
+async fn append_message(channel_id: u64, message: &ChatMessage) -> Result<()> {
+    let evicted = {
+        // Held across the push and the trim so concurrent handlers can't trim
+        // each other's messages twice.
+        let _guard = HISTORY_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        storage().push_message(channel_id, message)?;
+
+        let config = config();
+        let identity = astro_identity();
+        let tools = tools::definitions();
+        let summary = summary::summary(channel_id)?.unwrap_or_default();
+        // The history has to fit whichever model ends up answering.
+        let budget = config
+            .models()
+            .map(|model| tokens::history_budget(model, &identity, &tools))
+            .min()
+            .unwrap_or_default()
+            .saturating_sub(tokens::estimate_tokens(&summary))
+            .saturating_sub(config.memory.recall_tokens);
+        let mut messages = storage().messages(channel_id)?;
+        let overflow = tokens::overflowing_messages(&messages, budget);
+        if overflow > 0 {
+            storage().remove_oldest_messages(channel_id, overflow)?;
+        }
+
+        messages.truncate(overflow);
+        messages
+    };
 
-    while previous_messages.len() > 20 {
-        previous_messages.remove(0);
+    if !evicted.is_empty() {
+        upkeep(channel_id, evicted);
     }
+    Ok(())
+}
 
-    std::fs::write(
-        "messages.json",
-        serde_json::to_string_pretty(&previous_messages).unwrap(),
-    )
-    .unwrap();
+// Summarizes and remembers evicted messages in the background, so that the
+// response doesn't wait on another model call. Each channel has a task which
+// takes the batches one at a time, in the order they were evicted.
+fn upkeep(channel_id: u64, evicted: Vec<ChatMessage>) {
+    let mut upkeep = UPKEEP.lock().unwrap();
+    let queue = upkeep.entry(channel_id).or_insert_with(|| {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<ChatMessage>>();
+        tokio::spawn(async move {
+            while let Some(evicted) = receiver.recv().await {
+                // Losing the evicted context isn't worth more than a log line.
+                if let Err(why) = summary::fold(channel_id, &evicted).await {
+                    println!("Error summarizing history: {:?}", why);
+                }
+                if let Err(why) = memory::remember_messages(channel_id, &evicted).await {
+                    println!("Error storing memories: {:?}", why);
+                }
+            }
+        });
+        sender
+    });
+    if let Err(why) = queue.send(evicted) {
+        println!("Error queueing evicted messages: {:?}", why);
+    }
 }
 
-fn opinions() -> HashMap<String, u8> {
-    std::fs::read_to_string("opinions.json")
-        .ok()
-        .and_then(|opinions| serde_json::from_str::<HashMap<String, u8>>(&opinions).ok())
-        .unwrap_or_default()
+pub fn user_opinion(user_id: u64) -> Result<u8> {
+    Ok(storage()
+        .opinion(user_id)?
+        .unwrap_or(config().opinions.default))
 }
 
-fn user_opinion(user_id: u64) -> u8 {
-    opinions().get(&user_id.to_string()).cloned().unwrap_or(50)
+pub fn increment_user_opinion(user_id: u64) -> Result<u8> {
+    let config = config();
+    let opinions = &config.opinions;
+    storage().update_opinion(user_id, &|opinion| {
+        opinion
+            .unwrap_or(opinions.default)
+            .saturating_add(opinions.step)
+            .min(100)
+    })
 }
 
-fn increment_user_opinion(user_id: u64) {
-    let mut opinions = opinions();
-    let user_id = user_id.to_string();
-    let new_opinion = (opinions.get(&user_id.to_string()).cloned().unwrap_or(50) + 15).min(100);
-    opinions.insert(user_id, new_opinion);
-
-    std::fs::write(
-        "opinions.json",
-        serde_json::to_string_pretty(&opinions).unwrap(),
-    )
-    .unwrap();
+pub fn decrement_user_opinion(user_id: u64) -> Result<u8> {
+    let config = config();
+    let opinions = &config.opinions;
+    storage().update_opinion(user_id, &|opinion| {
+        opinion
+            .unwrap_or(opinions.default)
+            .saturating_sub(opinions.step)
+    })
 }
 
-fn decrement_user_opinion(user_id: u64) {
-    let mut opinions = opinions();
-    let user_id = user_id.to_string();
-    let new_opinion = opinions
-        .get(&user_id.to_string())
-        .cloned()
-        .unwrap_or(50)
-        .saturating_sub(15);
-    opinions.insert(user_id, new_opinion);
-
-    std::fs::write(
-        "opinions.json",
-        serde_json::to_string_pretty(&opinions).unwrap(),
-    )
-    .unwrap();
+pub fn reset(channel_id: u64) -> Result<()> {
+    storage().clear_messages(channel_id)?;
+    conversation::reset(channel_id)?;
+    summary::clear(channel_id)
 }
 
-pub fn reset() {
-    std::fs::write("messages.json", "[]").unwrap();
+// Describes the message being replied to, so that the model knows what
+// "this" refers to.
+async fn reply_context(ctx: &Context, message: &Message, names: &Names) -> Option<String> {
+    let referenced = message.referenced_message.as_ref()?;
+
+    // Referenced messages don't carry a guild id, so the nickname has to be
+    // looked up in the guild of the reply.
+    let author = if referenced.is_own(ctx).await.unwrap_or_default() {
+        "Astro (you)".to_string()
+    } else {
+        match message.guild_id {
+            Some(guild_id) => referenced.author.nick_in(ctx, guild_id).await,
+            None => None,
+        }
+        .unwrap_or(referenced.author.name.clone())
+    };
+
+    let mut lines = vec![
+        "[replying to this message]".to_string(),
+        format!("author: {author}"),
+        format!("sent: {}", referenced.timestamp),
+        format!(
+            "content: {}",
+            names.readable(&referenced.content, &referenced.mentions)
+        ),
+    ];
+    if let Some(summary) = attachments::summary(&referenced.attachments) {
+        lines.push(format!("attachments: {summary}"));
+    }
+    Some(lines.join("\n"))
 }
 
 pub async fn respond(
@@ -120,238 +231,407 @@ pub async fn respond(
     message: Message,
     force_call: Option<&'static str>,
 ) -> Result<()> {
-    if !message.requires_response(ctx).await? {
-        if ACTIVE_CONVO.load(Ordering::Relaxed) != message.channel_id.0 {
-            ACTIVE_CONVO.store(0, Ordering::Relaxed);
-            return Ok(());
-        }
+    let addressing = message.addressing(ctx).await?;
+    if !conversation::on_message(message.channel_id.0, addressing)? {
+        return Ok(());
     }
 
     let author = message
         .author_nick(&ctx)
         .await
         .unwrap_or(message.author.name.clone());
-    let new_message = ChatCompletionMessage {
-        role: ChatCompletionMessageRole::User,
-        content: Some(format!(
-            "{}: {}",
-            user_opinion(message.author.id.0),
-            message.content.clone()
-        )),
-        name: Some(author),
-        function_call: None,
-    };
+    let names = mentions::names(ctx, message.guild_id).await?;
+    let content = names.readable(&message.content, &message.mentions);
+    // Checked before anything is added to the history, so that chatter
+    // between other people doesn't cost a full completion.
+    if addressing == Addressing::None && !attention::is_for_astro(&message, &author, &content).await
+    {
+        return Ok(());
+    }
+
+    // Only taken once Astro is going to answer, so that messages it ignores
+    // don't wait for a response in progress.
+    let lock = channel_lock(message.channel_id.0);
+    let _guard = lock.lock().await;
+
+    let mut parts = vec![ContentPart::text(format!(
+        "{}: {}",
+        user_opinion(message.author.id.0)?,
+        content
+    ))];
+    if let Some(context) = reply_context(ctx, &message, &names).await {
+        parts.push(ContentPart::text(context));
+    }
+    parts.extend(attachments::file_parts(&message).await);
+    parts.extend(attachments::image_parts(&message));
+    let new_message = ChatMessage::user(Some(author), parts);
     // Add message to history
-    append_message(&new_message);
+    append_message(message.channel_id.0, &new_message).await?;
 
     query_model(ctx, &message, force_call).await
 }
 
-#[async_recursion]
+// Added to the results of the round which hits a limit, so that the model
+// later sees why it didn't get to look at them.
+const LIMIT_NOTE: &str =
+    "The tool limit was reached before you could look at this result. Use fewer tool calls for a single message.";
+
+// Replaces 🤔 when a response fails.
+const ERROR_REACTION: char = '❌';
+
+// How a single completion ended.
+enum Round {
+    Done,
+    FollowUp,
+    // Ran out of tool rounds or time before the model was done.
+    Limited,
+}
+
+// Queries the model until it stops calling tools, it runs out of tool rounds
+// or the response takes too long.
 pub async fn query_model(
     ctx: &Context,
     message: &Message,
-    force_call: Option<&'static str>,
+    mut force_call: Option<&'static str>,
 ) -> Result<()> {
+    let config = config();
+    let model = match usage::check(message.author.id.0, message.channel_id.0)? {
+        Quota::Allowed => config.model.clone(),
+        Quota::Downgrade(model) => {
+            println!(
+                "{} is over quota, answering with {}",
+                message.author.name, model
+            );
+            model
+        }
+        Quota::Declined => {
+            println!("{} is over quota, declining", message.author.name);
+            message
+                .reply_maybe_long(ctx, config.usage.decline_reply.clone())
+                .await?;
+            return Ok(());
+        }
+    };
+    let deadline = Instant::now() + Duration::from_secs(config.tools.timeout_seconds);
+    // Results of the last round's tool calls. They are saved along with the
+    // next reply, or with a note once a limit makes clear there won't be one.
+    let mut pending = Vec::new();
+
+    for round in 1..=config.tools.max_rounds {
+        let last_round = round == config.tools.max_rounds;
+        let result = query_round(
+            ctx,
+            message,
+            &model,
+            force_call.take(),
+            deadline,
+            last_round,
+            &mut pending,
+        )
+        .await;
+        match result {
+            Ok(Round::Done) => return Ok(()),
+            Ok(Round::FollowUp) => {}
+            Ok(Round::Limited) => break,
+            Err(why) => {
+                // Otherwise it looks like Astro is still thinking.
+                message.delete_reaction_emoji(ctx, '🤔').await.ok();
+                message.react(ctx, ERROR_REACTION).await.ok();
+                return Err(why);
+            }
+        }
+    }
+
+    println!("Tool limit reached in channel {}", message.channel_id.0);
+    let channel_id = message.channel_id.0;
+    for result in pending {
+        append_message(channel_id, &with_limit_note(result)).await?;
+    }
+    let fallback_reply = config.tools.fallback_reply.clone();
+    append_message(channel_id, &ChatMessage::assistant(fallback_reply.clone())).await?;
+    message.reply_maybe_long(ctx, fallback_reply).await?;
+    Ok(())
+}
+
+fn with_limit_note(result: ChatMessage) -> ChatMessage {
+    let text = format!("{}\n\n{LIMIT_NOTE}", result.text().unwrap_or_default());
+    ChatMessage::tool(result.tool_call_id.unwrap_or_default(), text)
+}
+
+// Gets the model's reply, streaming its content into the channel when that is
+// enabled. Returns the reply along with whether its content was already
+// shown, or None if the deadline passed first.
+async fn complete(
+    ctx: &Context,
+    message: &Message,
+    request: &ChatRequest,
+    deadline: Instant,
+) -> Result<Option<(ChatResponse, bool)>> {
+    if !config().streaming.enabled {
+        let Ok(response) = timeout_at(deadline, provider().complete(request)).await else {
+            return Ok(None);
+        };
+        return Ok(Some((response?, false)));
+    }
+
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    // Owns the sender so that the writer stops once the completion is done.
+    let completion = async move {
+        let on_delta = |delta: &str| {
+            sender.send(delta.to_string()).ok();
+        };
+        timeout_at(deadline, provider().stream(request, &on_delta)).await
+    };
+    let writer = async {
+        let mut reply = StreamingReply::new(ctx, message).await?;
+        while let Some(delta) = receiver.recv().await {
+            reply.push(&delta).await?;
+        }
+        reply.finish().await
+    };
+
+    let (response, written) = tokio::join!(completion, writer);
+    let Ok(response) = response else {
+        return Ok(None);
+    };
+    let response = response?;
+    written?;
+
+    let shown = response.message.content.is_some();
+    Ok(Some((response, shown)))
+}
+
+async fn query_round(
+    ctx: &Context,
+    message: &Message,
+    model: &str,
+    force_call: Option<&'static str>,
+    deadline: Instant,
+    last_round: bool,
+    pending: &mut Vec<ChatMessage>,
+) -> Result<Round> {
     // React with eyes to indicate the bot saw this.
     message.react(ctx, '🤔').await?;
 
     // Setup identity
-    let mut messages = vec![ChatCompletionMessage {
-        role: ChatCompletionMessageRole::System,
-        content: Some(astro_identity()),
+    let mut messages = vec![ChatMessage {
         name: Some("Astro".to_string()),
-        function_call: None,
+        ..ChatMessage::system(astro_identity())
     }];
 
-    // Add previous messages
-    messages.append(&mut previous_messages());
-
-    let chat_completion = ChatCompletion::builder("gpt-3.5-turbo", messages)
-        .functions(vec! [
-            ChatCompletionFunctionDefinition {
-                name: "react".to_string(),
-                description: Some("Takes a string with a single emoji and reacts to the last message in the transcript with it.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {
-                        "emoji": {
-                            "type": "string",
-                            "description": "The emoji to react with."
-                        }
-                    },
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "pin".to_string(),
-                description: Some("Pins the message the last message was replying to. If that fails, a reason why is returned.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {},
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "unpin".to_string(),
-                description: Some("Unpins the message the last message was replying to. If that fails, a reason why is returned.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {},
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "get_users".to_string(),
-                description: Some("Gets the users in the chat. Returns a list of users.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {},
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "angry".to_string(),
-                description: Some("Marks that the author of the most recent message has been rude or mean.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {},
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "happy".to_string(),
-                description: Some("Marks that the author of the most recent message has been kind or nice.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {},
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "get_opinion".to_string(),
-                description: Some("Gets the opinion of a user by name. Returns a number from 0 to 100 with 0 meaning that the user is very rude and 100 meaning they are very kind.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {
-                        "name": {
-                            "type": "string",
-                            "description": "The name of the user to get the opinion of.",
-                        },
-                    },
-                })),
-            },
-            ChatCompletionFunctionDefinition {
-                name: "stop_listening".to_string(),
-                description: Some("Stops listening to the chat. Should be called when the last message isn't directed at Astro.".to_string()),
-                parameters: Some(json!({
-                    "type": "object",
-                    "properties": {},
-                })),
-            },
-        ])
-        .function_call(force_call.map(|function| json!({"name": function})).unwrap_or(json!("auto")))
-        .create()
-        .await?;
-
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    // Add response to history
-    append_message(&returned_message);
-
-    message.delete_reaction_emoji(ctx, '🤔').await?;
-
-    ACTIVE_CONVO.store(message.channel_id.into(), Ordering::Relaxed);
-
-    if let Some(function_call) = returned_message.function_call.as_ref() {
-        match function_call.name.as_str() {
-            "react" => {
-                let arguments = serde_json::from_str::<Value>(&function_call.arguments).unwrap();
-                let reaction = arguments["emoji"].as_str().unwrap();
-
-                if let Some(possible_emoji) = reaction.chars().next() {
-                    // Response might not be a valid emoji. Ignore if not
-                    message.react(&ctx.http, possible_emoji).await.ok();
-                }
-                dbg!(reaction);
-            }
-            "pin" => {
-                if let Some(referenced_message) = message.referenced_message.as_ref() {
-                    referenced_message.pin(&ctx.http).await.ok();
-                    dbg!("pinned message");
-                    query_model(&ctx, message, None).await?;
-                } else {
-                    append_message(&ChatCompletionMessage {
-                        role: ChatCompletionMessageRole::Function,
-                        content: Some("Last message was not a reply.".to_string()),
-                        name: Some("pin".to_string()),
-                        function_call: None,
-                    });
-                    dbg!("pin failed");
-                    query_model(&ctx, message, None).await?;
-                }
-            }
-            "unpin" => {
-                if let Some(referenced_message) = message.referenced_message.as_ref() {
-                    referenced_message.unpin(&ctx.http).await.ok();
-                    dbg!("unpinned message");
-                    query_model(&ctx, message, None).await?;
-                } else {
-                    append_message(&ChatCompletionMessage {
-                        role: ChatCompletionMessageRole::Function,
-                        content: Some("Last message was not a reply.".to_string()),
-                        name: Some("unpin".to_string()),
-                        function_call: None,
-                    });
-                    dbg!("unpin failed");
-                    query_model(&ctx, message, None).await?;
-                }
-            }
-            "get_users" => {
-                append_message(&ChatCompletionMessage {
-                    role: ChatCompletionMessageRole::Function,
-                    content: Some(format!(
-                        "[{}]",
-                        USERS.keys().cloned().collect::<Vec<_>>().join(", ")
-                    )),
-                    name: Some("get_users".to_string()),
-                    function_call: None,
-                });
-                query_model(&ctx, message, None).await?;
-            }
-            "angry" => {
-                decrement_user_opinion(message.author.id.0);
-                query_model(&ctx, message, None).await?;
-            }
-            "happy" => {
-                increment_user_opinion(message.author.id.0);
-                query_model(&ctx, message, None).await?;
-            }
-            "get_opinion" => {
-                let arguments = serde_json::from_str::<Value>(&function_call.arguments).unwrap();
-                let name = arguments["name"].as_str().unwrap();
-
-                let mut opinion = 50;
-                for (user, id) in USERS.iter() {
-                    if user.to_lowercase() == name.to_lowercase() {
-                        opinion = user_opinion(*id as u64);
-                    }
-                }
+    // Add the summary of anything that has fallen out of the history
+    if let Some(summary) = summary::summary(message.channel_id.0)? {
+        messages.push(ChatMessage::system(format!(
+            "Summary of the conversation so far: {summary}"
+        )));
+    }
 
-                append_message(&ChatCompletionMessage {
-                    role: ChatCompletionMessageRole::Function,
-                    content: Some(opinion.to_string()),
-                    name: Some("get_opinion".to_string()),
-                    function_call: None,
-                });
-                query_model(&ctx, message, None).await?;
-            }
+    // Add long term memories related to the message
+    let guild_id = message.guild_id.map(|guild_id| guild_id.0);
+    let memories = memory::recall(message.channel_id.0, guild_id, &message.content)
+        .await
+        .unwrap_or_else(|why| {
+            println!("Error recalling memories: {:?}", why);
+            Vec::new()
+        });
+    if !memories.is_empty() {
+        messages.push(ChatMessage::system(format!(
+            "Things you remember which might be relevant:\n{}",
+            memories
+                .iter()
+                .map(|memory| format!("- {memory}"))
+                .join("\n")
+        )));
+    }
+
+    // Add previous messages. Images are only looked at when they are first
+    // sent, older ones are left as their placeholders. That also keeps
+    // expired attachment links out of requests.
+    let history = previous_messages(message.channel_id.0, pending)?;
+    let latest = history
+        .iter()
+        .rposition(|message| message.role == Role::User);
+    messages.extend(history.into_iter().enumerate().map(|(index, message)| {
+        if Some(index) == latest {
+            message
+        } else {
+            message.without_images()
+        }
+    }));
+
+    let request = ChatRequest {
+        tools: tools::definitions(),
+        tool_choice: Some(
+            force_call
+                .map(|tool| json!({"type": "function", "function": {"name": tool}}))
+                .unwrap_or(json!("auto")),
+        ),
+        ..ChatRequest::new(model, messages)
+    };
+    // Nothing has been added to the history yet, so a completion which takes
+    // too long can simply be dropped. The caller notes the limit in the
+    // pending results.
+    let Some((response, shown)) = complete(ctx, message, &request, deadline).await? else {
+        message.delete_reaction_emoji(ctx, '🤔').await.ok();
+        return Ok(Round::Limited);
+    };
+    if let Err(why) = usage::record(
+        Some(message.author.id.0),
+        message.channel_id.0,
+        &request,
+        &response,
+    ) {
+        println!("Error recording usage: {:?}", why);
+    }
+
+    let returned_message = response.message;
+    // Add the results this reply looked at and the reply to history
+    for result in pending.drain(..) {
+        append_message(message.channel_id.0, &result).await?;
+    }
+    append_message(message.channel_id.0, &returned_message).await?;
+
+    // A failure here would leave the tool calls without results. Removing
+    // reactions isn't possible in direct messages.
+    message.delete_reaction_emoji(ctx, '🤔').await.ok();
 
-            "stop_listening" => {
-                dbg!("stopped listening");
-                ACTIVE_CONVO.store(0, Ordering::Relaxed);
+    if let Err(why) = conversation::replied(message.channel_id.0) {
+        println!("Error updating conversation: {:?}", why);
+    }
+
+    // The calls in one response don't depend on each other, so they run at
+    // the same time.
+    let context = &ToolContext { ctx, message };
+    let tool_calls = returned_message.tool_calls();
+    let outcomes = join_all(tool_calls.iter().map(|tool_call| async move {
+        timeout_at(deadline, tools::run(context, &tool_call.function))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Ran out of time")))
+    }))
+    .await;
+
+    let mut follow_up = false;
+    let mut results = Vec::new();
+    for (tool_call, outcome) in tool_calls.iter().zip(outcomes) {
+        let result = match outcome {
+            Ok(outcome) => {
+                follow_up |= outcome.follow_up;
+                outcome.result
             }
-            _ => {}
+            Err(why) => {
+                println!("Error running {}: {:?}", tool_call.function.name, why);
+                follow_up = true;
+                json!({ "error": "failed", "message": format!("{why:#}") }).to_string()
+            }
+        };
+        results.push(result);
+    }
+
+    follow_up &= !tool_calls
+        .iter()
+        .any(|tool_call| tools::ends_conversation(&tool_call.function));
+    let limited = follow_up && (last_round || Instant::now() >= deadline);
+
+    // Every call needs a result in the history, otherwise the next request
+    // is rejected. Results the model will look at are saved with its reply.
+    pending.extend(
+        tool_calls
+            .iter()
+            .zip(results)
+            .map(|(tool_call, result)| ChatMessage::tool(tool_call.id.clone(), result)),
+    );
+    if !follow_up {
+        for result in pending.drain(..) {
+            append_message(message.channel_id.0, &result).await?;
         }
     }
 
-    if let Some(response) = returned_message.content.as_ref() {
-        message.reply_maybe_long(&ctx, response.clone()).await?;
+    if let Some(response) = returned_message.text() {
+        if !shown {
+            message.reply_maybe_long(&ctx, response.to_string()).await?;
+        }
         dbg!(response);
     }
 
-    Ok(())
+    // Let the model look at all of the results at once.
+    Ok(if limited {
+        Round::Limited
+    } else if follow_up {
+        Round::FollowUp
+    } else {
+        Round::Done
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{FunctionCall, ToolCall};
+
+    fn calling(ids: &[&str]) -> ChatMessage {
+        ChatMessage {
+            content: None,
+            tool_calls: Some(
+                ids.iter()
+                    .map(|id| ToolCall {
+                        id: id.to_string(),
+                        kind: "function".to_string(),
+                        function: FunctionCall {
+                            name: "react".to_string(),
+                            arguments: "{}".to_string(),
+                        },
+                    })
+                    .collect(),
+            ),
+            ..ChatMessage::assistant("")
+        }
+    }
+
+    fn roles(messages: &[ChatMessage]) -> Vec<Role> {
+        messages.iter().map(|message| message.role).collect()
+    }
+
+    #[test]
+    fn keeps_tool_calls_with_all_of_their_results() {
+        let messages = vec![
+            ChatMessage::user(None, "hi".to_string()),
+            calling(&["a", "b"]),
+            ChatMessage::tool("b", "done"),
+            ChatMessage::tool("a", "done"),
+            ChatMessage::assistant("hello"),
+        ];
+        assert_eq!(
+            roles(&answered_tool_calls(messages.into_iter())),
+            vec![
+                Role::User,
+                Role::Assistant,
+                Role::Tool,
+                Role::Tool,
+                Role::Assistant
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_unanswered_calls_and_stray_results() {
+        let messages = vec![
+            ChatMessage::tool("x", "stray"),
+            ChatMessage::user(None, "hi".to_string()),
+            calling(&["a", "b"]),
+            ChatMessage::tool("a", "done"),
+            ChatMessage::user(None, "again".to_string()),
+            calling(&["c"]),
+        ];
+        assert_eq!(
+            roles(&answered_tool_calls(messages.into_iter())),
+            vec![Role::User, Role::User]
+        );
+    }
+
+    #[test]
+    fn limit_note_is_added_to_the_result() {
+        let result = with_limit_note(ChatMessage::tool("a", "Pinned."));
+        assert_eq!(result.tool_call_id.as_deref(), Some("a"));
+        assert_eq!(result.text().unwrap(), format!("Pinned.\n\n{LIMIT_NOTE}"));
+    }
 }
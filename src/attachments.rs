@@ -0,0 +1,344 @@
+use std::iter;
+
+use chardetng::EncodingDetector;
+use encoding_rs::Encoding;
+use serenity::model::prelude::{Attachment, Message};
+
+use crate::{
+    config::config,
+    provider::{ContentPart, ImageUrl},
+    tokens,
+};
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+// File extensions worth reading, with the language used for their code fence.
+const TEXT_EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text"),
+    ("log", "log"),
+    ("md", "markdown"),
+    ("rs", "rust"),
+    ("toml", "toml"),
+    ("py", "python"),
+    ("js", "javascript"),
+    ("ts", "typescript"),
+    ("json", "json"),
+    ("yaml", "yaml"),
+    ("yml", "yaml"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cpp", "cpp"),
+    ("hpp", "cpp"),
+    ("cs", "csharp"),
+    ("go", "go"),
+    ("java", "java"),
+    ("kt", "kotlin"),
+    ("lua", "lua"),
+    ("rb", "ruby"),
+    ("sh", "bash"),
+    ("ps1", "powershell"),
+    ("html", "html"),
+    ("css", "css"),
+    ("sql", "sql"),
+    ("xml", "xml"),
+    ("csv", "csv"),
+    ("ini", "ini"),
+    ("cfg", "ini"),
+    ("diff", "diff"),
+    ("patch", "diff"),
+];
+
+fn extension(attachment: &Attachment) -> Option<String> {
+    attachment
+        .filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+}
+
+fn is_image(attachment: &Attachment) -> bool {
+    match attachment.content_type.as_deref() {
+        Some(content_type) => content_type.starts_with("image/"),
+        None => extension(attachment)
+            .map(|extension| IMAGE_EXTENSIONS.contains(&extension.as_str()))
+            .unwrap_or_default(),
+    }
+}
+
+// Parts for the images attached to a message. Every image gets a placeholder
+// with its file name, which is all that models without vision see.
+pub fn image_parts(message: &Message) -> Vec<ContentPart> {
+    let config = config();
+    let mut parts = Vec::new();
+    let mut included = 0;
+
+    for attachment in message
+        .attachments
+        .iter()
+        .filter(|attachment| is_image(attachment))
+    {
+        let skipped = if attachment.size > config.vision.max_image_bytes {
+            Some("too large to look at")
+        } else if included >= config.vision.max_images {
+            Some("too many images to look at")
+        } else {
+            None
+        };
+
+        match skipped {
+            Some(reason) => parts.push(ContentPart::text(format!(
+                "[image: {}, {reason}]",
+                attachment.filename
+            ))),
+            None => {
+                parts.push(ContentPart::text(format!(
+                    "[image: {}]",
+                    attachment.filename
+                )));
+                parts.push(ContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: attachment.url.clone(),
+                        detail: Some(config.vision.detail.clone()),
+                    },
+                });
+                included += 1;
+            }
+        }
+    }
+
+    parts
+}
+
+fn text_language(attachment: &Attachment) -> Option<&'static str> {
+    let extension = extension(attachment);
+    if let Some((_, language)) = TEXT_EXTENSIONS
+        .iter()
+        .find(|(known, _)| Some(*known) == extension.as_deref())
+    {
+        return Some(language);
+    }
+
+    match attachment.content_type.as_deref() {
+        Some(content_type) if content_type.starts_with("text/") => Some("text"),
+        Some(content_type) if content_type.starts_with("application/json") => Some("json"),
+        _ => None,
+    }
+}
+
+// Files without a telling extension can still say what they are on their
+// first line.
+fn shebang_language(text: &str) -> Option<&'static str> {
+    let first_line = text.lines().next()?.strip_prefix("#!")?;
+    ["python", "bash", "node", "ruby", "lua"]
+        .into_iter()
+        .find(|interpreter| first_line.contains(interpreter))
+        .map(|interpreter| match interpreter {
+            "node" => "javascript",
+            interpreter => interpreter,
+        })
+}
+
+// Decodes a file and names the encoding it was in. Byte order marks are
+// trusted, then utf-8 is tried, then the encoding is guessed from the bytes.
+// Returns None for files which look binary.
+fn decode(bytes: &[u8]) -> Option<(String, &'static str)> {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        return Some((text.into_owned(), encoding.name()));
+    }
+    if bytes.contains(&0) {
+        return None;
+    }
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Some((text.to_string(), "UTF-8"));
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, true);
+    let (text, _, _) = encoding.decode(bytes);
+    Some((text.into_owned(), encoding.name()))
+}
+
+// Keeps as many whole lines from the start as fit in the budget, or the
+// start of the first line when even that is too long.
+fn truncate(text: &str, budget: usize) -> (String, Option<String>) {
+    if tokens::estimate_tokens(text) <= budget {
+        return (text.to_string(), None);
+    }
+
+    let total_lines = text.lines().count();
+    let mut kept = String::new();
+    let mut used = 0;
+    let mut kept_lines = 0;
+    for line in text.lines() {
+        let cost = tokens::estimate_tokens(line) + 1;
+        if used + cost > budget {
+            break;
+        }
+        kept.push_str(line);
+        kept.push('\n');
+        used += cost;
+        kept_lines += 1;
+    }
+
+    // Not even the first line fits, so it gets cut short instead. Estimates
+    // only grow as the text does, which allows a binary search.
+    if kept_lines == 0 {
+        let first_line = text.lines().next().unwrap_or_default();
+        let boundaries: Vec<usize> = first_line
+            .char_indices()
+            .map(|(index, _)| index)
+            .chain(iter::once(first_line.len()))
+            .collect();
+        let fitting = boundaries
+            .partition_point(|end| tokens::estimate_tokens(&first_line[..*end]) <= budget);
+        let end = boundaries[fitting.saturating_sub(1)];
+        let note = "[truncated: only the start of the first line fit, the rest was left out]";
+        return (first_line[..end].to_string(), Some(note.to_string()));
+    }
+
+    let note = format!(
+        "[truncated: only the first {kept_lines} of {total_lines} lines fit, the rest was left out]"
+    );
+    (kept, Some(note))
+}
+
+// A fence longer than any run of backticks in the text, so the text can't
+// close it early.
+fn fence(text: &str) -> String {
+    let mut longest = 0;
+    let mut current = 0;
+    for c in text.chars() {
+        current = if c == '`' { current + 1 } else { 0 };
+        longest = longest.max(current);
+    }
+    "`".repeat((longest + 1).max(3))
+}
+
+// A one line description of a message's attachments, for when the model only
+// needs to know they are there.
+pub fn summary(attachments: &[Attachment]) -> Option<String> {
+    if attachments.is_empty() {
+        return None;
+    }
+
+    Some(
+        attachments
+            .iter()
+            .map(|attachment| {
+                let kind = if is_image(attachment) {
+                    "image"
+                } else if text_language(attachment).is_some() {
+                    "text file"
+                } else {
+                    "file"
+                };
+                format!(
+                    "{} ({kind}, {} KB)",
+                    attachment.filename,
+                    attachment.size.div_ceil(1024)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+// Parts for the text files attached to a message, each inlined as a fenced
+// block. Files share a token budget. Anything which doesn't fit is truncated
+// and the model is told so.
+pub async fn file_parts(message: &Message) -> Vec<ContentPart> {
+    let config = config();
+    let files = &config.files;
+    let mut parts = Vec::new();
+    let mut budget = files.max_tokens;
+    let mut read = 0;
+
+    for attachment in message.attachments.iter() {
+        let Some(language) = text_language(attachment) else {
+            continue;
+        };
+
+        let skipped = if read >= files.max_files {
+            Some("too many files to read".to_string())
+        } else if attachment.size > files.max_file_bytes {
+            Some(format!("too large to read ({} KB)", attachment.size / 1024))
+        } else if budget == 0 {
+            Some("out of room to read it".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = skipped {
+            parts.push(ContentPart::text(format!(
+                "[file: {}, {reason}]",
+                attachment.filename
+            )));
+            continue;
+        }
+
+        let bytes = match attachment.download().await {
+            Ok(bytes) => bytes,
+            Err(why) => {
+                println!("Error downloading {}: {:?}", attachment.filename, why);
+                parts.push(ContentPart::text(format!(
+                    "[file: {}, could not be downloaded]",
+                    attachment.filename
+                )));
+                continue;
+            }
+        };
+        read += 1;
+
+        let Some((text, encoding)) = decode(&bytes) else {
+            parts.push(ContentPart::text(format!(
+                "[file: {}, binary]",
+                attachment.filename
+            )));
+            continue;
+        };
+        let language = match language {
+            "text" => shebang_language(&text).unwrap_or(language),
+            language => language,
+        };
+
+        let (text, note) = truncate(&text, budget);
+        budget = budget.saturating_sub(tokens::estimate_tokens(&text));
+
+        let fence = fence(&text);
+        let mut part = format!(
+            "[file: {}, {language}, {encoding}]\n{fence}{language}\n{}",
+            attachment.filename,
+            text.trim_end()
+        );
+        part.push_str(&format!("\n{fence}"));
+        if let Some(note) = note {
+            part.push_str(&format!("\n{note}"));
+        }
+        parts.push(ContentPart::text(part));
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_whole_lines_which_fit() {
+        let (kept, note) = truncate("aaaaaaaa\nbbbbbbbb\ncccccccc\n", 6);
+        assert_eq!(kept, "aaaaaaaa\nbbbbbbbb\n");
+        assert!(note.unwrap().contains("first 2 of 3 lines"));
+        assert_eq!(truncate("short", 10), ("short".to_string(), None));
+    }
+
+    #[test]
+    fn cuts_a_first_line_which_is_too_long() {
+        let (kept, note) = truncate(&"a".repeat(100), 10);
+        assert_eq!(kept, "a".repeat(40));
+        assert!(note.unwrap().contains("start of the first line"));
+
+        let (kept, _) = truncate(&"é".repeat(100), 10);
+        assert_eq!(kept, "é".repeat(10));
+    }
+}
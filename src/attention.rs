@@ -0,0 +1,158 @@
+use std::iter;
+
+use anyhow::Result;
+use indoc::indoc;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serenity::model::prelude::Message;
+
+use crate::{
+    config::{config, AttentionConfig},
+    provider::{provider, ChatMessage, ChatRequest},
+    storage::storage,
+    summary, usage,
+};
+
+const CLASSIFIER_PROMPT: &str = indoc! {"
+    You decide whether Astro, a chat bot in a Discord channel, should answer
+    the last message of a conversation. Astro has been talking in the channel,
+    so the message may be meant for it even if it doesn't use Astro's name.
+    Reply yes if the last message is directed at Astro or follows up on
+    something Astro said, and no if it is meant for someone else or for nobody
+    in particular. Reply with yes or no only.
+"};
+
+lazy_static! {
+    // Links and Discord markup, which can contain a wake word without anyone
+    // saying it.
+    static ref NOT_WORDS: Regex = Regex::new(r"https?://\S+|<[^>]*>").unwrap();
+}
+
+// Whether the text uses one of the wake words as a word of its own, so that
+// "astro" counts but "astronomy" and links don't.
+pub fn has_wake_word(text: &str) -> bool {
+    matches_wake_word(&config().attention, text)
+}
+
+fn matches_wake_word(settings: &AttentionConfig, text: &str) -> bool {
+    let Some(pattern) = settings.wake_pattern() else {
+        return false;
+    };
+    pattern.is_match(&NOT_WORDS.replace_all(text, " "))
+}
+
+// Answers for messages which are obviously not for Astro, without asking a
+// model. None when it takes a closer look.
+fn heuristic(message: &Message) -> Option<bool> {
+    // Astro isn't pinged or replied to, or this wouldn't be asked, so these
+    // are for someone else.
+    if !message.mentions.is_empty()
+        || !message.mention_roles.is_empty()
+        || message.mention_everyone
+        || message.referenced_message.is_some()
+    {
+        return Some(false);
+    }
+    // Stickers and the like.
+    if message.content.trim().is_empty() && message.attachments.is_empty() {
+        return Some(false);
+    }
+    None
+}
+
+async fn classify(message: &Message, model: &str, author: &str, text: &str) -> Result<bool> {
+    let channel_id = message.channel_id.0;
+    let context = config().attention.classifier_context;
+    let history = storage().messages(channel_id)?;
+    let earlier = history
+        .iter()
+        .filter_map(summary::transcript_line)
+        .collect::<Vec<_>>();
+    let transcript = earlier[earlier.len().saturating_sub(context)..]
+        .iter()
+        .cloned()
+        .chain(iter::once(format!("{author}: {text}")))
+        .join("\n");
+
+    let request = ChatRequest::new(
+        model,
+        vec![
+            ChatMessage::system(CLASSIFIER_PROMPT),
+            ChatMessage::user(None, transcript),
+        ],
+    );
+    let response = provider().complete(&request).await?;
+    if let Err(why) = usage::record(Some(message.author.id.0), channel_id, &request, &response) {
+        println!("Error recording usage: {:?}", why);
+    }
+
+    Ok(response
+        .message
+        .text()
+        .map(|answer| !answer.trim().to_lowercase().starts_with("no"))
+        .unwrap_or(true))
+}
+
+// Decides whether a message which doesn't address Astro is still meant for
+// it, in a channel where Astro is part of the conversation. The obvious cases
+// are settled by heuristics, and the rest by the classifier model if there is
+// one. When in doubt the message counts as meant for Astro, so that the full
+// model gets to decide.
+pub async fn is_for_astro(message: &Message, author: &str, text: &str) -> bool {
+    if let Some(answer) = heuristic(message) {
+        return answer;
+    }
+
+    let Some(model) = config().attention.classifier_model.clone() else {
+        return true;
+    };
+    match classify(message, &model, author, text).await {
+        Ok(answer) => answer,
+        Err(why) => {
+            println!("Error classifying message: {:?}", why);
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(words: &[&str]) -> AttentionConfig {
+        let mut settings = AttentionConfig::default();
+        settings.wake_words = words.iter().map(|word| word.to_string()).collect();
+        settings
+    }
+
+    #[test]
+    fn wake_words_only_count_as_whole_words() {
+        let settings = settings(&["astro"]);
+        assert!(matches_wake_word(&settings, "hey Astro, how are you?"));
+        assert!(matches_wake_word(&settings, "ASTRO"));
+        assert!(!matches_wake_word(&settings, "I love astronomy"));
+        assert!(!matches_wake_word(&settings, "gastro"));
+    }
+
+    #[test]
+    fn wake_words_in_links_and_markup_are_ignored() {
+        let settings = settings(&["astro"]);
+        assert!(!matches_wake_word(
+            &settings,
+            "see https://astro.build/docs"
+        ));
+        assert!(!matches_wake_word(
+            &settings,
+            "hi <@astro> and <:astro:123>"
+        ));
+        assert!(matches_wake_word(&settings, "<#1> astro, look at this"));
+    }
+
+    #[test]
+    fn wake_words_are_escaped() {
+        let settings = settings(&["c++", "astro"]);
+        assert!(matches_wake_word(&settings, "astro"));
+        assert!(!matches_wake_word(&settings, "cpp"));
+    }
+}
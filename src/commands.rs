@@ -0,0 +1,30 @@
+use anyhow::Result;
+use serenity::{model::prelude::Message, prelude::Context};
+
+use crate::{config::config, extensions::MessageExt, summary, usage, users};
+
+// Handles maintenance commands. Returns true if the message was a command and
+// shouldn't be passed on to the model.
+pub async fn handle(ctx: &Context, message: &Message) -> Result<bool> {
+    let channel_id = message.channel_id.0;
+
+    let reply = match message.content.trim() {
+        "!summary" => summary::summary(channel_id)?
+            .unwrap_or_else(|| "Nothing has been summarized in this channel yet.".to_string()),
+        "!summary clear" => {
+            summary::clear(channel_id)?;
+            "Cleared the summary for this channel.".to_string()
+        }
+        // Spend is only reported to admins.
+        "!spend" if config().admin_channel == Some(channel_id) => {
+            usage::report(&users::names_by_id(ctx, message.guild_id).await?)?
+        }
+        _ => return Ok(false),
+    };
+
+    // The spend report grows with every user and channel, so it may not fit
+    // in one message.
+    message.reply_maybe_long(ctx, reply).await?;
+
+    Ok(true)
+}
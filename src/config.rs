@@ -0,0 +1,881 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_model")]
+    pub model: String,
+    // Tried in order when the model keeps failing.
+    #[serde(default)]
+    pub fallback_models: Vec<String>,
+    #[serde(default = "default_identity")]
+    pub identity: PathBuf,
+    // Channel config reloads are announced in.
+    #[serde(default)]
+    pub admin_channel: Option<u64>,
+    // Channel the morning greeting and identity rules are tied to.
+    #[serde(default)]
+    pub bridge_channel: Option<u64>,
+    // Channels where Astro only reacts with emoji.
+    #[serde(default)]
+    pub muted_channels: Vec<u64>,
+    pub purge: PurgeConfig,
+    #[serde(default)]
+    pub opinions: OpinionConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub provider: ProviderConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    #[serde(default)]
+    pub tools: ToolsConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub streaming: StreamingConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub vision: VisionConfig,
+    #[serde(default)]
+    pub files: FilesConfig,
+    #[serde(default)]
+    pub replies: RepliesConfig,
+    #[serde(default)]
+    pub mentions: MentionsConfig,
+    #[serde(default)]
+    pub members: MembersConfig,
+    #[serde(default)]
+    pub conversation: ConversationConfig,
+    #[serde(default)]
+    pub attention: AttentionConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PurgeConfig {
+    #[serde(default = "default_purge_emoji")]
+    pub emoji: String,
+    // Messages in this category take more votes to purge.
+    pub category: u64,
+    #[serde(default = "default_category_limit")]
+    pub category_limit: usize,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpinionConfig {
+    pub default: u8,
+    pub step: u8,
+}
+
+impl Default for OpinionConfig {
+    fn default() -> Self {
+        Self {
+            default: 50,
+            step: 15,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    // Context window sizes by model name prefix. The longest matching prefix
+    // wins, and unknown models get default_context_size.
+    pub context_sizes: HashMap<String, usize>,
+    pub default_context_size: usize,
+    // Room left in the context window for the model's reply.
+    pub response_tokens: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            context_sizes: HashMap::from([
+                ("gpt-3.5-turbo".to_string(), 4096),
+                ("gpt-3.5-turbo-16k".to_string(), 16384),
+                ("gpt-4".to_string(), 8192),
+                ("gpt-4-32k".to_string(), 32768),
+            ]),
+            default_context_size: 4096,
+            response_tokens: 512,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+    OpenAi,
+    // Any server implementing the OpenAI chat completions api.
+    Compatible,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    pub kind: ProviderKind,
+    // Required for compatible providers, optional override for OpenAI.
+    pub base_url: Option<String>,
+    // Environment variable holding the api key. Defaults to OPENAI_KEY for
+    // OpenAI, and no key for compatible providers.
+    pub api_key_env: Option<String>,
+}
+
+impl Default for ProviderConfig {
+    fn default() -> Self {
+        Self {
+            kind: ProviderKind::OpenAi,
+            base_url: None,
+            api_key_env: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    // SQLite database to store state in. The json files in the working
+    // directory are used when this isn't set.
+    pub database: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedderKind {
+    Hash,
+    OpenAi,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    pub embedder: EmbedderKind,
+    // How many memories are pulled into the prompt, and the most tokens they
+    // may take up together.
+    pub recall_count: usize,
+    pub recall_tokens: usize,
+    // Memories kept before the oldest are forgotten.
+    pub max_memories: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            embedder: EmbedderKind::Hash,
+            recall_count: 4,
+            recall_tokens: 256,
+            max_memories: 10000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolsConfig {
+    // Completions per message which may call tools before Astro gives up.
+    pub max_rounds: usize,
+    // Time a whole response may take, tool calls included.
+    pub timeout_seconds: u64,
+    // Sent when either limit cuts a response short.
+    pub fallback_reply: String,
+}
+
+impl Default for ToolsConfig {
+    fn default() -> Self {
+        Self {
+            max_rounds: 5,
+            timeout_seconds: 60,
+            fallback_reply: "Sorry, I got a bit lost there. Could you ask me again?".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    // Tries per model, including the first one.
+    pub attempts: usize,
+    // The wait between tries starts here and doubles up to max_backoff_ms.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // Time a single completion request may take.
+    pub timeout_seconds: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 10000,
+            timeout_seconds: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamingConfig {
+    // Shows replies while they are generated instead of all at once.
+    pub enabled: bool,
+    // Least time between edits of a streamed reply.
+    pub edit_interval_ms: u64,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            edit_interval_ms: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    // Dollars per thousand tokens.
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    // Politely refuse to answer.
+    Decline,
+    // Answer with downgrade_model instead.
+    Downgrade,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsageConfig {
+    // Prices by model name prefix, matched like history.context_sizes. Models
+    // without a price are counted as free.
+    pub prices: HashMap<String, Price>,
+    // Spending limits in dollars. Days and months are in UTC.
+    pub daily_user_limit: Option<f64>,
+    pub monthly_user_limit: Option<f64>,
+    pub daily_channel_limit: Option<f64>,
+    pub monthly_channel_limit: Option<f64>,
+    // Budget for everything together. Astro always declines once it is spent.
+    pub monthly_limit: Option<f64>,
+    // What happens when a user or channel limit is reached.
+    pub over_quota: QuotaAction,
+    pub downgrade_model: Option<String>,
+    pub decline_reply: String,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            prices: HashMap::from([
+                (
+                    "gpt-3.5-turbo".to_string(),
+                    Price {
+                        prompt: 0.0015,
+                        completion: 0.002,
+                    },
+                ),
+                (
+                    "gpt-3.5-turbo-16k".to_string(),
+                    Price {
+                        prompt: 0.003,
+                        completion: 0.004,
+                    },
+                ),
+                (
+                    "gpt-4".to_string(),
+                    Price {
+                        prompt: 0.03,
+                        completion: 0.06,
+                    },
+                ),
+                (
+                    "gpt-4-32k".to_string(),
+                    Price {
+                        prompt: 0.06,
+                        completion: 0.12,
+                    },
+                ),
+            ]),
+            daily_user_limit: None,
+            monthly_user_limit: None,
+            daily_channel_limit: None,
+            monthly_channel_limit: None,
+            monthly_limit: None,
+            over_quota: QuotaAction::Decline,
+            downgrade_model: None,
+            decline_reply: "I've talked enough for now, let's pick this up later.".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct VisionConfig {
+    // Prefixes of the models which can look at images. Other models get a
+    // placeholder with the file name instead.
+    pub models: Vec<String>,
+    // Images per message, and the largest image that is passed on.
+    pub max_images: usize,
+    pub max_image_bytes: u64,
+    // "low", "high" or "auto". Low detail is much cheaper.
+    pub detail: String,
+}
+
+impl Default for VisionConfig {
+    fn default() -> Self {
+        Self {
+            models: vec![
+                "gpt-4-vision".to_string(),
+                "gpt-4-turbo".to_string(),
+                "gpt-4o".to_string(),
+            ],
+            max_images: 4,
+            max_image_bytes: 10 * 1024 * 1024,
+            detail: "low".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    // Text attachments read per message, and the largest one downloaded.
+    pub max_files: usize,
+    pub max_file_bytes: u64,
+    // Tokens the files of one message may take up together. Files past this
+    // are truncated.
+    pub max_tokens: usize,
+}
+
+impl Default for FilesConfig {
+    fn default() -> Self {
+        Self {
+            max_files: 3,
+            max_file_bytes: 256 * 1024,
+            max_tokens: 1500,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepliesConfig {
+    // Replies which would take more messages than this are uploaded as a
+    // file instead.
+    pub max_messages: usize,
+    // Also uploads replies which don't fit in one message and are mostly code.
+    pub upload_code: bool,
+}
+
+impl Default for RepliesConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 3,
+            upload_code: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MentionsConfig {
+    // Whether replies may ping the people Astro knows about. Anyone else is
+    // never pinged.
+    pub users: bool,
+    // Channels where replies may ping @everyone and @here.
+    pub everyone_channels: Vec<u64>,
+    // Channels where replies may ping roles.
+    pub role_channels: Vec<u64>,
+}
+
+impl Default for MentionsConfig {
+    fn default() -> Self {
+        Self {
+            users: true,
+            everyone_channels: Vec::new(),
+            role_channels: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MembersConfig {
+    // Whether to subscribe to member events, which keep member names up to
+    // date. They need the privileged server members intent. Without them the
+    // member lists are fetched again every so often.
+    pub events: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConversationConfig {
+    // Astro stops listening to a channel once it has been quiet this long.
+    pub idle_timeout_seconds: u64,
+    // Messages in a row which aren't addressed to Astro before it stops
+    // listening.
+    pub max_unaddressed: u32,
+    // How long after it stops listening only direct pings and replies start
+    // the conversation again.
+    pub cooldown_seconds: u64,
+}
+
+impl Default for ConversationConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_seconds: 300,
+            max_unaddressed: 5,
+            cooldown_seconds: 120,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttentionConfig {
+    // Words which get Astro's attention. They only count as whole words, so
+    // "astro" doesn't match "astronomy".
+    pub wake_words: Vec<String>,
+    // Small model which decides whether messages that don't address Astro are
+    // still meant for it while it is in a conversation. Without one, they all
+    // go to the main model.
+    pub classifier_model: Option<String>,
+    // Earlier messages the classifier sees along with the new one.
+    pub classifier_context: usize,
+    // Built from wake_words the first time it is needed, so once per load.
+    #[serde(skip)]
+    wake_pattern: OnceLock<Option<Regex>>,
+}
+
+impl Default for AttentionConfig {
+    fn default() -> Self {
+        Self {
+            wake_words: vec!["astro".to_string()],
+            classifier_model: None,
+            classifier_context: 4,
+            wake_pattern: OnceLock::new(),
+        }
+    }
+}
+
+impl AttentionConfig {
+    // Matches any of the wake words as a whole word, ignoring case.
+    pub fn wake_pattern(&self) -> Option<&Regex> {
+        self.wake_pattern
+            .get_or_init(|| {
+                let words = self
+                    .wake_words
+                    .iter()
+                    .map(|word| regex::escape(word))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                Regex::new(&format!(r"(?i)\b(?:{words})\b")).ok()
+            })
+            .as_ref()
+    }
+}
+
+fn default_model() -> String {
+    "gpt-3.5-turbo".to_string()
+}
+
+fn default_identity() -> PathBuf {
+    PathBuf::from("identity.txt")
+}
+
+fn default_purge_emoji() -> String {
+    "purge".to_string()
+}
+
+fn default_category_limit() -> usize {
+    5
+}
+
+fn default_limit() -> usize {
+    3
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+        let config: Config =
+            toml::from_str(&contents).with_context(|| format!("Could not parse {:?}", path))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid config in {:?}", path))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.model.trim().is_empty() {
+            bail!("model must not be empty");
+        }
+        if self
+            .fallback_models
+            .iter()
+            .any(|model| model.trim().is_empty())
+        {
+            bail!("fallback_models must not contain empty names");
+        }
+        if self.muted_channels.contains(&0)
+            || self.bridge_channel == Some(0)
+            || self.admin_channel == Some(0)
+            || self.mentions.everyone_channels.contains(&0)
+            || self.mentions.role_channels.contains(&0)
+        {
+            bail!("channel ids must not be 0");
+        }
+        for (name, channels) in [
+            ("muted_channels", &self.muted_channels),
+            (
+                "mentions.everyone_channels",
+                &self.mentions.everyone_channels,
+            ),
+            ("mentions.role_channels", &self.mentions.role_channels),
+        ] {
+            if let Some(channel) = channels
+                .iter()
+                .enumerate()
+                .find(|(index, channel)| channels[..*index].contains(channel))
+                .map(|(_, channel)| channel)
+            {
+                bail!("{} lists channel {} more than once", name, channel);
+            }
+        }
+        if self.purge.category == 0 {
+            bail!("purge.category must not be 0");
+        }
+        if self.purge.limit == 0 || self.purge.category_limit == 0 {
+            bail!("purge.limit and purge.category_limit must be at least 1");
+        }
+        if self.opinions.default > 100 {
+            bail!(
+                "opinions.default must be between 0 and 100, got {}",
+                self.opinions.default
+            );
+        }
+        if self.opinions.step == 0 || self.opinions.step > 100 {
+            bail!(
+                "opinions.step must be between 1 and 100, got {}",
+                self.opinions.step
+            );
+        }
+        for model in self.models() {
+            if self.context_size(model) <= self.history.response_tokens {
+                bail!(
+                    "the context size for {} must be larger than history.response_tokens ({})",
+                    model,
+                    self.history.response_tokens
+                );
+            }
+        }
+        if self.provider.kind == ProviderKind::Compatible && self.provider.base_url.is_none() {
+            bail!("provider.base_url is required when provider.kind is \"compatible\"");
+        }
+        if self.memory.recall_count == 0 && self.memory.recall_tokens > 0 {
+            bail!("memory.recall_tokens must be 0 when memory.recall_count is 0");
+        }
+        if self.memory.max_memories == 0 {
+            bail!("memory.max_memories must be at least 1");
+        }
+        if self.tools.max_rounds == 0 || self.tools.timeout_seconds == 0 {
+            bail!("tools.max_rounds and tools.timeout_seconds must be at least 1");
+        }
+        if self.tools.fallback_reply.trim().is_empty() {
+            bail!("tools.fallback_reply must not be empty");
+        }
+        if self.retry.attempts == 0 || self.retry.timeout_seconds == 0 {
+            bail!("retry.attempts and retry.timeout_seconds must be at least 1");
+        }
+        if self.retry.initial_backoff_ms > self.retry.max_backoff_ms {
+            bail!("retry.initial_backoff_ms must not be larger than retry.max_backoff_ms");
+        }
+        let limits = [
+            self.usage.daily_user_limit,
+            self.usage.monthly_user_limit,
+            self.usage.daily_channel_limit,
+            self.usage.monthly_channel_limit,
+            self.usage.monthly_limit,
+        ];
+        if limits.into_iter().flatten().any(|limit| limit < 0.0) {
+            bail!("usage limits must not be negative");
+        }
+        if self
+            .usage
+            .prices
+            .values()
+            .any(|price| price.prompt < 0.0 || price.completion < 0.0)
+        {
+            bail!("usage.prices must not be negative");
+        }
+        if self.usage.over_quota == QuotaAction::Downgrade && self.usage.downgrade_model.is_none() {
+            bail!("usage.downgrade_model is required when usage.over_quota is \"downgrade\"");
+        }
+        if self.usage.decline_reply.trim().is_empty() {
+            bail!("usage.decline_reply must not be empty");
+        }
+        if !["low", "high", "auto"].contains(&self.vision.detail.as_str()) {
+            bail!(
+                "vision.detail must be \"low\", \"high\" or \"auto\", got {:?}",
+                self.vision.detail
+            );
+        }
+        // Files are part of the latest message, which is always kept in the
+        // history, so they have to leave room for everything else.
+        let smallest_context = self
+            .models()
+            .map(|model| self.context_size(model))
+            .min()
+            .unwrap_or_default();
+        if self.files.max_tokens * 2 > smallest_context.saturating_sub(self.history.response_tokens)
+        {
+            bail!("files.max_tokens must be at most half of the smallest context left after history.response_tokens");
+        }
+        if self.attention.wake_words.is_empty()
+            || self
+                .attention
+                .wake_words
+                .iter()
+                .any(|word| word.trim().is_empty())
+        {
+            bail!("attention.wake_words must contain at least one word and no empty ones");
+        }
+        if self
+            .attention
+            .classifier_model
+            .as_deref()
+            .is_some_and(|model| model.trim().is_empty())
+        {
+            bail!("attention.classifier_model must not be empty");
+        }
+        if self.conversation.idle_timeout_seconds < 1 {
+            bail!("conversation.idle_timeout_seconds must be at least 1");
+        }
+        if self.replies.max_messages < 1 {
+            bail!("replies.max_messages must be at least 1");
+        }
+        // Discord allows about five edits every five seconds.
+        if self.streaming.edit_interval_ms < 1000 {
+            bail!("streaming.edit_interval_ms must be at least 1000");
+        }
+        Ok(())
+    }
+
+    pub fn context_size(&self, model: &str) -> usize {
+        longest_prefix(&self.history.context_sizes, model)
+            .cloned()
+            .unwrap_or(self.history.default_context_size)
+    }
+
+    pub fn price(&self, model: &str) -> Option<Price> {
+        longest_prefix(&self.usage.prices, model).cloned()
+    }
+
+    pub fn supports_vision(&self, model: &str) -> bool {
+        self.vision
+            .models
+            .iter()
+            .any(|prefix| model.starts_with(prefix.as_str()))
+    }
+
+    // Every model a reply may come from, starting with the configured one.
+    pub fn models(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.model)
+            .chain(self.fallback_models.iter())
+            .chain(self.usage.downgrade_model.iter())
+    }
+}
+
+// Looks up a per model setting by the longest prefix of the model name.
+fn longest_prefix<'a, T>(settings: &'a HashMap<String, T>, model: &str) -> Option<&'a T> {
+    settings
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, setting)| setting)
+}
+
+fn load_identity(path: &Path) -> Result<String> {
+    let identity =
+        std::fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+    if identity.trim().is_empty() {
+        bail!("{:?} is empty", path);
+    }
+    Ok(identity)
+}
+
+struct Loaded {
+    path: PathBuf,
+    config: Arc<Config>,
+    identity: Arc<String>,
+}
+
+static LOADED: OnceLock<RwLock<Loaded>> = OnceLock::new();
+
+pub fn config_path() -> PathBuf {
+    PathBuf::from(env::var("CONFIG_PATH").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string()))
+}
+
+// Reads the config from CONFIG_PATH, or config.toml in the working directory,
+// along with the identity it points to.
+pub fn init() -> Result<()> {
+    let path = config_path();
+    let config = Config::load(&path)?;
+    let identity = load_identity(&config.identity)?;
+
+    LOADED
+        .set(RwLock::new(Loaded {
+            path,
+            config: Arc::new(config),
+            identity: Arc::new(identity),
+        }))
+        .map_err(|_| anyhow::anyhow!("Config was already loaded"))
+}
+
+fn loaded() -> &'static RwLock<Loaded> {
+    LOADED.get().expect("Config used before it was loaded")
+}
+
+// Re-reads the config and identity. Both are validated before either is
+// swapped in, so a broken file leaves the last good versions in place.
+// Returns a note for settings which only take effect after a restart.
+pub fn reload() -> Result<Option<String>> {
+    let path = loaded().read().unwrap().path.clone();
+    let config = Config::load(&path)?;
+    let identity = load_identity(&config.identity)?;
+
+    let mut loaded = loaded().write().unwrap();
+    let mut restart_needed = Vec::new();
+    if config.provider != loaded.config.provider {
+        restart_needed.push("provider");
+    }
+    if config.storage != loaded.config.storage {
+        restart_needed.push("storage");
+    }
+    if config.memory.embedder != loaded.config.memory.embedder {
+        restart_needed.push("memory.embedder");
+    }
+    if config.members != loaded.config.members {
+        restart_needed.push("members");
+    }
+
+    loaded.config = Arc::new(config);
+    loaded.identity = Arc::new(identity);
+
+    Ok((!restart_needed.is_empty()).then(|| {
+        format!(
+            "Changes to {} take effect after a restart.",
+            restart_needed.join(", ")
+        )
+    }))
+}
+
+pub fn config() -> Arc<Config> {
+    loaded().read().unwrap().config.clone()
+}
+
+pub fn identity() -> Arc<String> {
+    loaded().read().unwrap().identity.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = "[purge]\ncategory = 1\n";
+
+    // Settings outside of a table have to come before the minimal config.
+    fn parse(settings: &str) -> Result<Config> {
+        let config: Config = toml::from_str(&format!("{settings}\n{MINIMAL}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn error(settings: &str) -> String {
+        format!("{:#}", parse(settings).unwrap_err())
+    }
+
+    #[test]
+    fn minimal_config_is_valid() {
+        let config = parse("").unwrap();
+        assert_eq!(config.model, "gpt-3.5-turbo");
+        assert_eq!(config.purge.category, 1);
+        assert_eq!(config.attention.wake_words, vec!["astro"]);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(error("modle = \"gpt-4\"").contains("unknown field"));
+        assert!(error("[opinions]\nstpe = 10").contains("unknown field"));
+    }
+
+    #[test]
+    fn missing_purge_category_is_rejected() {
+        assert!(toml::from_str::<Config>("[purge]\n").is_err());
+    }
+
+    #[test]
+    fn channels_are_checked() {
+        assert!(error("muted_channels = [0]").contains("must not be 0"));
+        assert!(error("muted_channels = [5, 6, 5]").contains("muted_channels lists channel 5"));
+        assert!(error("[mentions]\nrole_channels = [7, 7]")
+            .contains("mentions.role_channels lists channel 7"));
+        assert!(parse("muted_channels = [5, 6]\n[mentions]\nrole_channels = [5]").is_ok());
+    }
+
+    #[test]
+    fn thresholds_are_checked() {
+        assert!(error("[opinions]\ndefault = 101").contains("opinions.default"));
+        assert!(error("[opinions]\nstep = 0").contains("opinions.step"));
+        assert!(error("[tools]\nmax_rounds = 0").contains("tools.max_rounds"));
+        assert!(
+            error("[retry]\ninitial_backoff_ms = 2000\nmax_backoff_ms = 1000")
+                .contains("retry.initial_backoff_ms")
+        );
+        assert!(error("[usage]\ndaily_user_limit = -1.0").contains("must not be negative"));
+        assert!(error("[conversation]\nidle_timeout_seconds = 0")
+            .contains("conversation.idle_timeout_seconds"));
+        assert!(error("[streaming]\nedit_interval_ms = 500").contains("streaming.edit_interval_ms"));
+    }
+
+    #[test]
+    fn context_sizes_must_leave_room_for_the_reply() {
+        // Models without a context size get the default one.
+        assert!(error(
+            "model = \"local\"\n[history]\ndefault_context_size = 100\nresponse_tokens = 100"
+        )
+        .contains("history.response_tokens"));
+        assert!(error("[history]\nresponse_tokens = 4096").contains("history.response_tokens"));
+    }
+
+    #[test]
+    fn dependent_settings_are_checked() {
+        assert!(error("[provider]\nkind = \"compatible\"").contains("provider.base_url"));
+        assert!(error("[usage]\nover_quota = \"downgrade\"").contains("usage.downgrade_model"));
+        assert!(error("[attention]\nwake_words = []").contains("attention.wake_words"));
+        assert!(error("[vision]\ndetail = \"medium\"").contains("vision.detail"));
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{config, ConversationConfig},
+    storage::{now, storage},
+};
+
+// How directly a message speaks to Astro.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Addressing {
+    None,
+    // Mentions Astro's name in the text.
+    ByName,
+    // Pings Astro or replies to one of its messages.
+    Directly,
+}
+
+// How Astro is taking part in the conversation in a channel. Every channel
+// has its own, and they are stored so that a restart doesn't forget them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Conversation {
+    // Only answers messages addressed to Astro.
+    #[default]
+    Idle,
+    // Answers every message, until the channel goes quiet or too many
+    // messages in a row aren't addressed to Astro.
+    Engaged {
+        // When Astro was last addressed or last replied, in unix seconds.
+        last_active: i64,
+        // Messages since Astro was last addressed.
+        unaddressed: u32,
+    },
+    // Just stopped listening. Messages using Astro's name still get an
+    // answer, but until then only direct pings and replies start the
+    // conversation again. Otherwise the name coming up in the chatter which
+    // made Astro stop would pull it right back in.
+    CoolingDown {
+        until: i64,
+    },
+}
+
+impl Conversation {
+    fn cooling_down(now: i64, settings: &ConversationConfig) -> Self {
+        Self::CoolingDown {
+            until: now + settings.cooldown_seconds as i64,
+        }
+    }
+
+    fn on_message(self, addressing: Addressing, now: i64, settings: &ConversationConfig) -> Self {
+        let engaged = Self::Engaged {
+            last_active: now,
+            unaddressed: 0,
+        };
+
+        match (self, addressing) {
+            (_, Addressing::Directly) => engaged,
+            // Answered, but without starting the conversation again.
+            (Self::CoolingDown { until }, _) if now < until => self,
+            (_, Addressing::ByName) => engaged,
+            (
+                Self::Engaged {
+                    last_active,
+                    unaddressed,
+                },
+                Addressing::None,
+            ) => {
+                if now - last_active >= settings.idle_timeout_seconds as i64 {
+                    Self::Idle
+                } else if unaddressed >= settings.max_unaddressed {
+                    Self::cooling_down(now, settings)
+                } else {
+                    Self::Engaged {
+                        last_active,
+                        unaddressed: unaddressed + 1,
+                    }
+                }
+            }
+            (_, Addressing::None) => Self::Idle,
+        }
+    }
+
+    fn replied(self, now: i64) -> Self {
+        match self {
+            Self::Engaged { .. } => Self::Engaged {
+                last_active: now,
+                unaddressed: 0,
+            },
+            conversation => conversation,
+        }
+    }
+}
+
+// Moves the channel's conversation along for a new message. Returns whether
+// Astro should answer it, which it always does when addressed.
+pub fn on_message(channel_id: u64, addressing: Addressing) -> Result<bool> {
+    let now = now();
+    let conversation = storage().update_conversation(channel_id, &|conversation| {
+        conversation
+            .unwrap_or_default()
+            .on_message(addressing, now, &config().conversation)
+    })?;
+    Ok(addressing != Addressing::None || matches!(conversation, Conversation::Engaged { .. }))
+}
+
+// Keeps the conversation going after Astro replied in it. A message Astro
+// answered was for it, so it doesn't count towards the unaddressed ones.
+pub fn replied(channel_id: u64) -> Result<()> {
+    let now = now();
+    storage().update_conversation(channel_id, &|conversation| {
+        conversation.unwrap_or_default().replied(now)
+    })?;
+    Ok(())
+}
+
+// Stops listening to the channel, for when the conversation has moved on.
+pub fn stop(channel_id: u64) -> Result<()> {
+    let now = now();
+    storage().update_conversation(channel_id, &|_| {
+        Conversation::cooling_down(now, &config().conversation)
+    })?;
+    Ok(())
+}
+
+pub fn reset(channel_id: u64) -> Result<()> {
+    storage().update_conversation(channel_id, &|_| Conversation::Idle)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_000_000;
+
+    fn settings() -> ConversationConfig {
+        ConversationConfig {
+            idle_timeout_seconds: 300,
+            max_unaddressed: 2,
+            cooldown_seconds: 120,
+        }
+    }
+
+    fn engaged(last_active: i64, unaddressed: u32) -> Conversation {
+        Conversation::Engaged {
+            last_active,
+            unaddressed,
+        }
+    }
+
+    #[test]
+    fn being_addressed_engages() {
+        let settings = settings();
+        for addressing in [Addressing::ByName, Addressing::Directly] {
+            assert_eq!(
+                Conversation::Idle.on_message(addressing, NOW, &settings),
+                engaged(NOW, 0)
+            );
+        }
+        assert_eq!(
+            Conversation::Idle.on_message(Addressing::None, NOW, &settings),
+            Conversation::Idle
+        );
+    }
+
+    #[test]
+    fn only_direct_addressing_ends_a_cooldown() {
+        let settings = settings();
+        let cooling_down = Conversation::CoolingDown { until: NOW + 60 };
+        assert_eq!(
+            cooling_down.on_message(Addressing::Directly, NOW, &settings),
+            engaged(NOW, 0)
+        );
+        assert_eq!(
+            cooling_down.on_message(Addressing::ByName, NOW, &settings),
+            cooling_down
+        );
+        assert_eq!(
+            cooling_down.on_message(Addressing::None, NOW, &settings),
+            cooling_down
+        );
+
+        let over = Conversation::CoolingDown { until: NOW };
+        assert_eq!(
+            over.on_message(Addressing::ByName, NOW, &settings),
+            engaged(NOW, 0)
+        );
+        assert_eq!(
+            over.on_message(Addressing::None, NOW, &settings),
+            Conversation::Idle
+        );
+    }
+
+    #[test]
+    fn goes_idle_after_the_timeout() {
+        let settings = settings();
+        assert_eq!(
+            engaged(NOW - 300, 0).on_message(Addressing::None, NOW, &settings),
+            Conversation::Idle
+        );
+        assert_eq!(
+            engaged(NOW - 299, 0).on_message(Addressing::None, NOW, &settings),
+            engaged(NOW - 299, 1)
+        );
+    }
+
+    #[test]
+    fn cools_down_after_too_many_unaddressed_messages() {
+        let settings = settings();
+        let mut conversation = engaged(NOW, 0);
+        for _ in 0..2 {
+            conversation = conversation.on_message(Addressing::None, NOW, &settings);
+        }
+        assert_eq!(conversation, engaged(NOW, 2));
+        assert_eq!(
+            conversation.on_message(Addressing::None, NOW, &settings),
+            Conversation::CoolingDown { until: NOW + 120 }
+        );
+    }
+
+    #[test]
+    fn replying_resets_the_unaddressed_count() {
+        let settings = settings();
+        let mut conversation = engaged(NOW, 0);
+        for _ in 0..10 {
+            conversation = conversation
+                .on_message(Addressing::None, NOW, &settings)
+                .replied(NOW);
+        }
+        assert_eq!(conversation, engaged(NOW, 0));
+
+        let cooling_down = Conversation::CoolingDown { until: NOW + 60 };
+        assert_eq!(cooling_down.replied(NOW), cooling_down);
+        assert_eq!(Conversation::Idle.replied(NOW), Conversation::Idle);
+    }
+}
@@ -1,12 +1,16 @@
 use anyhow::{Context as AnyhowContext, Result};
-use serenity::{async_trait, model::prelude::Message, prelude::Context};
+use serenity::{
+    async_trait,
+    model::prelude::{AttachmentType, Message},
+    prelude::Context,
+};
 
-use crate::ai::USERS;
+use crate::{attention, config::config, conversation::Addressing, mentions, reply};
 
 #[async_trait]
 pub trait MessageExt {
     async fn is_own(&self, ctx: &Context) -> Result<bool>;
-    async fn requires_response(&self, ctx: &Context) -> Result<bool>;
+    async fn addressing(&self, ctx: &Context) -> Result<Addressing>;
     async fn reply_maybe_long(&self, ctx: &Context, response: String) -> Result<()>;
 }
 
@@ -16,47 +20,72 @@ impl MessageExt for Message {
         Ok(self.author.id == ctx.http.get_current_user().await?.id)
     }
 
-    async fn requires_response(&self, ctx: &Context) -> Result<bool> {
+    async fn addressing(&self, ctx: &Context) -> Result<Addressing> {
         let references_own_message = match self.referenced_message.as_ref() {
             Some(message) => message.is_own(&ctx).await.unwrap_or_default(),
             None => false,
         };
 
-        Ok(self.mentions_me(&ctx).await?
-            || references_own_message
-            || self.content.to_lowercase().contains("astro"))
+        Ok(if self.mentions_me(&ctx).await? || references_own_message {
+            Addressing::Directly
+        } else if attention::has_wake_word(&self.content) {
+            Addressing::ByName
+        } else {
+            Addressing::None
+        })
     }
 
-    async fn reply_maybe_long(&self, ctx: &Context, mut response: String) -> Result<()> {
-        for user in USERS.keys() {
-            response = response.replace(&format!("@{user}"), &format!("<@{}>", USERS[user]));
-        }
-
-        let mut replied = false;
-        while let Some(overflow_length) = Message::overflow_length(&response) {
-            let (first, second) = response.split_at(response.len() - overflow_length);
-            if !replied {
-                self.reply(ctx, first)
-                    .await
-                    .context("Failed to send message")?;
-                replied = true;
-            } else {
-                self.channel_id
-                    .say(&ctx, first)
-                    .await
-                    .context("Failed to send message")?;
-            }
-
-            response = second.to_string();
+    async fn reply_maybe_long(&self, ctx: &Context, response: String) -> Result<()> {
+        let names = mentions::names(ctx, self.guild_id).await?;
+        let rendered = names.discord(&response);
+        let pings = names.pings(&rendered, self.channel_id.0);
+        if !pings.suppressed.is_empty() {
+            println!(
+                "Not pinging {:?} in {}",
+                pings.suppressed, self.channel_id.0
+            );
         }
 
-        if !replied {
-            self.reply(ctx, response)
+        let config = config();
+        let messages = reply::split(&rendered, reply::MESSAGE_LIMIT);
+        let upload = messages.len() > config.replies.max_messages
+            || (messages.len() > 1
+                && config.replies.upload_code
+                && reply::is_mostly_code(&rendered));
+        if upload {
+            // Whatever comes before the first code block usually introduces
+            // it, so that goes in the message. The file keeps readable names.
+            let introduction = rendered.split("```").next().unwrap_or_default().trim();
+            let content = match introduction.chars().count() {
+                1..=reply::MESSAGE_LIMIT => introduction,
+                _ => "The reply was too long for a message, so here it is as a file.",
+            };
+            self.channel_id
+                .send_message(ctx, |message| {
+                    message
+                        .reference_message(self)
+                        .content(content)
+                        .allowed_mentions(|allowed| pings.apply(allowed))
+                        .add_file(AttachmentType::Bytes {
+                            data: response.as_bytes().into(),
+                            filename: "reply.md".to_string(),
+                        })
+                })
                 .await
                 .context("Failed to send message")?;
-        } else {
+            return Ok(());
+        }
+
+        for (index, text) in messages.into_iter().enumerate() {
             self.channel_id
-                .say(&ctx, response)
+                .send_message(ctx, |message| {
+                    if index == 0 {
+                        message.reference_message(self);
+                    }
+                    message
+                        .content(text)
+                        .allowed_mentions(|allowed| pings.apply(allowed))
+                })
                 .await
                 .context("Failed to send message")?;
         }
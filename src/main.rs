@@ -1,16 +1,33 @@
 #![feature(let_chains)]
 
 mod ai;
+mod attachments;
+mod attention;
+mod commands;
+mod config;
+mod conversation;
 mod extensions;
+mod memory;
+mod mentions;
+mod provider;
+mod reload;
+mod reply;
+mod storage;
+mod summary;
+mod tools;
+mod usage;
+mod tokens;
+mod users;
 
 use std::env;
 
 use ai::{respond, reset};
+use config::config;
 use anyhow::Result;
 use dotenvy::dotenv;
 use serenity::{
     async_trait,
-    model::prelude::{Reaction, ReactionType},
+    model::prelude::{GuildId, GuildMemberUpdateEvent, Member, Reaction, ReactionType, User},
     prelude::{*, GatewayIntents},
     model::{channel::Message, gateway::Ready},
 };
@@ -20,8 +37,6 @@ use extensions::MessageExt;
 
 struct Handler;
 
-pub const BRIDGE: u64 = 598338172958670862;
-const MUTED_CHANNELS: &[u64] = &[598338172958670862, 636801468011249666, 972919610649231430];
 
 #[async_trait]
 impl EventHandler for Handler {
@@ -31,7 +46,11 @@ impl EventHandler for Handler {
                 return Ok(());
             }
 
-            if MUTED_CHANNELS.contains(&msg.channel_id.0) {
+            if commands::handle(&ctx, &msg).await? {
+                return Ok(());
+            }
+
+            if config().muted_channels.contains(&msg.channel_id.0) {
                 // react with emoji response
                 respond(&ctx, msg, Some("react")).await?;
                 return Ok(());
@@ -58,12 +77,12 @@ impl EventHandler for Handler {
             }
 
             if let ReactionType::Custom { name, .. } = &reaction.emoji
-                && name == &Some("purge".to_string()) {
+                && name == &Some(config().purge.emoji.clone()) {
 
-                let mut purge_limit = 5;
+                let mut purge_limit = config().purge.category_limit;
                 if let Ok(channel) = reaction.channel_id.to_channel(&ctx).await {
-                    if channel.guild().and_then(|guild_channel| guild_channel.parent_id).map(|parent_id| parent_id != 598667330826010624).unwrap_or_default() {
-                        purge_limit = 3;
+                    if channel.guild().and_then(|guild_channel| guild_channel.parent_id).map(|parent_id| parent_id != config().purge.category).unwrap_or_default() {
+                        purge_limit = config().purge.limit;
                     }
                 } 
 
@@ -100,7 +119,7 @@ impl EventHandler for Handler {
                     return Ok(());
                 }
 
-                reset();
+                reset(msg.channel_id.0)?;
 
                 msg.react(&ctx.http, ReactionType::Unicode("🤯".to_string()))
                     .await?;
@@ -114,9 +133,25 @@ impl EventHandler for Handler {
         }
     }
 
+    async fn guild_member_addition(&self, _ctx: Context, new_member: Member) {
+        users::add_member(&new_member);
+    }
+
+    async fn guild_member_update(&self, _ctx: Context, new: GuildMemberUpdateEvent) {
+        users::update_member(new.guild_id, new.nick.as_ref(), &new.user);
+    }
+
+    async fn guild_member_removal(&self, _ctx: Context, guild_id: GuildId, kicked: User) {
+        users::remove_member(guild_id, kicked.id);
+    }
+
     async fn ready(&self, context: Context, _ready: Ready) {
         println!("Connected!");
 
+        if let Err(why) = reload::watch(context.http.clone()) {
+            println!("Error watching config: {:?}", why);
+        }
+
         // let scheduler = JobScheduler::new()
         //     .await
         //     .expect("Could not create scheduler");
@@ -147,14 +182,26 @@ impl EventHandler for Handler {
 #[tokio::main]
 async fn main() {
     dotenv().unwrap();
-    ai::auth();
+    if let Err(why) = config::init() {
+        println!("Error loading config: {:?}", why);
+        std::process::exit(1);
+    }
+    provider::init();
+    storage::init();
+    memory::init();
+    users::init();
 
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
-    let intents = GatewayIntents::GUILD_MESSAGES
+    let mut intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::GUILD_MESSAGE_REACTIONS
         | GatewayIntents::DIRECT_MESSAGE_REACTIONS
         | GatewayIntents::MESSAGE_CONTENT;
+    // Privileged, so Discord refuses the connection unless it is enabled for
+    // the bot in the developer portal.
+    if config().members.events {
+        intents |= GatewayIntents::GUILD_MEMBERS;
+    }
 
     let mut client = Client::builder(&token, intents)
         .event_handler(Handler)
@@ -0,0 +1,375 @@
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use kdtree::{distance::squared_euclidean, KdTree};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+
+use crate::{
+    config::{config, EmbedderKind},
+    provider::{self, ChatMessage, OpenAiCompatibleProvider},
+    storage::storage,
+    summary::transcript_line,
+    tokens,
+};
+
+const HASH_DIMENSIONS: usize = 256;
+const OPENAI_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+
+// Embeddings are normalized, so squared distances range from 0 for identical
+// text to 4 for opposite text. Anything further than this isn't related
+// enough to be worth recalling.
+const MAX_DISTANCE: f64 = 1.5;
+
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    fn dimensions(&self) -> usize;
+    async fn embed(&self, text: &str) -> Result<Vec<f64>>;
+}
+
+// Deterministic embedder that hashes words and word pairs into a fixed number
+// of buckets. It only captures overlapping vocabulary, but needs no network
+// access.
+pub struct HashEmbedder {
+    dimensions: usize,
+}
+
+impl HashEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        Self { dimensions }
+    }
+}
+
+// FNV-1a, which unlike the std hasher is guaranteed to stay the same between
+// builds. Stored embeddings depend on that.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[async_trait]
+impl Embedder for HashEmbedder {
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f64>> {
+        let lowercase = text.to_lowercase();
+        let words: Vec<&str> = lowercase
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        let mut vector = vec![0.0; self.dimensions];
+        let features = words
+            .iter()
+            .map(|word| word.to_string())
+            .chain(words.windows(2).map(|pair| pair.join(" ")));
+        for feature in features {
+            let hash = fnv1a(&feature);
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimensions as u64) as usize] += sign;
+        }
+
+        normalize(&mut vector);
+        Ok(vector)
+    }
+}
+
+// Uses the embeddings endpoint of the configured provider.
+pub struct OpenAiEmbedder {
+    client: OpenAiCompatibleProvider,
+}
+
+impl OpenAiEmbedder {
+    pub fn new(client: OpenAiCompatibleProvider) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn dimensions(&self) -> usize {
+        1536
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f64>> {
+        let mut vector = self.client.embed(OPENAI_EMBEDDING_MODEL, text).await?;
+        normalize(&mut vector);
+        Ok(vector)
+    }
+}
+
+fn normalize(vector: &mut [f64]) {
+    let length = vector.iter().map(|value| value * value).sum::<f64>().sqrt();
+    if length > 0.0 {
+        for value in vector.iter_mut() {
+            *value /= length;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Memory {
+    // Where the memory is recalled: the guild for facts remembered in a
+    // server, and the channel for direct messages and for messages which fell
+    // out of a channel's history. Discord ids are unique across guilds and
+    // channels, so one id is enough. Facts from before they were scoped have
+    // none and aren't recalled anywhere.
+    #[serde(alias = "channel_id")]
+    pub scope: Option<u64>,
+    pub text: String,
+    pub vector: Vec<f64>,
+}
+
+// The memories are persisted as a flat list and the tree is rebuilt from it
+// on load.
+struct MemoryIndex {
+    dimensions: usize,
+    memories: Vec<Memory>,
+    tree: KdTree<f64, usize, Vec<f64>>,
+}
+
+impl MemoryIndex {
+    fn new(dimensions: usize) -> Self {
+        Self {
+            dimensions,
+            memories: Vec::new(),
+            tree: KdTree::new(dimensions),
+        }
+    }
+
+    fn load(dimensions: usize) -> Result<Self> {
+        let mut index = Self::new(dimensions);
+
+        let memories = storage().memories()?;
+        let total = memories.len();
+        for memory in memories {
+            // Memories embedded by a different embedder can't be compared.
+            if memory.vector.len() == dimensions && memory.scope.is_some() {
+                index.add(memory)?;
+            }
+        }
+        if index.memories.len() != total {
+            println!(
+                "Ignoring {} memories which are unscoped or have the wrong dimensions",
+                total - index.memories.len()
+            );
+        }
+
+        Ok(index)
+    }
+
+    fn add(&mut self, memory: Memory) -> Result<()> {
+        self.tree.add(memory.vector.clone(), self.memories.len())?;
+        self.memories.push(memory);
+        Ok(())
+    }
+
+    // Keeps only the newest memories. The tree can't remove points, so it is
+    // rebuilt from the ones which are left.
+    fn keep_newest(&mut self, count: usize) -> Result<()> {
+        if self.memories.len() <= count {
+            return Ok(());
+        }
+        let memories = self.memories.split_off(self.memories.len() - count);
+        *self = Self::new(self.dimensions);
+        for memory in memories {
+            self.add(memory)?;
+        }
+        Ok(())
+    }
+
+    fn nearest(
+        &self,
+        channel_id: u64,
+        guild_id: Option<u64>,
+        vector: &[f64],
+        count: usize,
+    ) -> Result<Vec<&Memory>> {
+        // Over fetch since memories from other channels get filtered out.
+        Ok(self
+            .tree
+            .nearest(vector, count * 4, &squared_euclidean)?
+            .into_iter()
+            .filter(|(distance, _)| *distance <= MAX_DISTANCE)
+            .map(|(_, index)| &self.memories[*index])
+            .filter(|memory| {
+                memory
+                    .scope
+                    .is_some_and(|scope| scope == channel_id || Some(scope) == guild_id)
+            })
+            .take(count)
+            .collect())
+    }
+}
+
+lazy_static! {
+    static ref EMBEDDER: Box<dyn Embedder> = match config().memory.embedder {
+        EmbedderKind::OpenAi => Box::new(OpenAiEmbedder::new(
+            provider::http_client().expect("Could not create the embeddings client")
+        )),
+        EmbedderKind::Hash => Box::new(HashEmbedder::new(HASH_DIMENSIONS)),
+    };
+    static ref INDEX: Mutex<MemoryIndex> =
+        Mutex::new(MemoryIndex::load(EMBEDDER.dimensions()).expect("Could not load memories"));
+}
+
+pub fn init() {
+    lazy_static::initialize(&INDEX);
+}
+
+// Embeds the texts and stores them in one write. The oldest memories are
+// forgotten once there are more than the configured maximum.
+async fn store(scope: u64, texts: Vec<String>) -> Result<()> {
+    let mut memories = Vec::new();
+    for text in texts {
+        memories.push(Memory {
+            scope: Some(scope),
+            vector: EMBEDDER.embed(&text).await?,
+            text,
+        });
+    }
+    if memories.is_empty() {
+        return Ok(());
+    }
+
+    let max_memories = config().memory.max_memories;
+    let mut index = INDEX.lock().unwrap();
+    storage().add_memories(&memories, max_memories)?;
+    for memory in memories {
+        index.add(memory)?;
+    }
+    index.keep_newest(max_memories)
+}
+
+// Stores a fact which is recalled in the guild it was remembered in, or in
+// the direct message channel.
+pub async fn remember_fact(scope: u64, fact: &str) -> Result<()> {
+    if fact.trim().is_empty() {
+        bail!("Nothing to remember");
+    }
+    store(scope, vec![fact.to_string()]).await
+}
+
+// Stores messages which fell out of a channel's history so they can be
+// recalled there later.
+pub async fn remember_messages(channel_id: u64, messages: &[ChatMessage]) -> Result<()> {
+    let lines = messages
+        .iter()
+        .filter_map(transcript_line)
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    store(channel_id, lines).await
+}
+
+// Returns the memories most relevant to the query which fit in the recall
+// token budget, out of those from the channel and the facts from its guild.
+pub async fn recall(channel_id: u64, guild_id: Option<u64>, query: &str) -> Result<Vec<String>> {
+    if query.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let vector = EMBEDDER.embed(query).await?;
+    let index = INDEX.lock().unwrap();
+
+    let mut remaining = config().memory.recall_tokens;
+    let mut recalled = Vec::new();
+    for memory in index.nearest(channel_id, guild_id, &vector, config().memory.recall_count)? {
+        let tokens = tokens::estimate_tokens(&memory.text);
+        if tokens > remaining {
+            break;
+        }
+        remaining -= tokens;
+        recalled.push(memory.text.clone());
+    }
+
+    Ok(recalled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(scope: Option<u64>, text: &str, vector: Vec<f64>) -> Memory {
+        Memory {
+            scope,
+            text: text.to_string(),
+            vector,
+        }
+    }
+
+    #[tokio::test]
+    async fn hash_embeddings_are_deterministic_and_normalized() {
+        let embedder = HashEmbedder::new(HASH_DIMENSIONS);
+        let first = embedder.embed("The cat sat on the mat").await.unwrap();
+        let second = embedder.embed("the cat  sat on the MAT!").await.unwrap();
+        assert_eq!(first.len(), HASH_DIMENSIONS);
+        assert_eq!(first, second);
+
+        let length = first.iter().map(|value| value * value).sum::<f64>();
+        assert!((length - 1.0).abs() < 1e-9);
+
+        let other = embedder.embed("Something else entirely").await.unwrap();
+        assert_ne!(first, other);
+        assert!(embedder
+            .embed("")
+            .await
+            .unwrap()
+            .iter()
+            .all(|value| *value == 0.0));
+    }
+
+    #[test]
+    fn nearest_only_returns_memories_in_scope() {
+        let mut index = MemoryIndex::new(2);
+        index
+            .add(memory(Some(1), "this channel", vec![1.0, 0.0]))
+            .unwrap();
+        index
+            .add(memory(Some(2), "other channel", vec![1.0, 0.0]))
+            .unwrap();
+        index
+            .add(memory(Some(10), "this guild", vec![0.9, 0.1]))
+            .unwrap();
+        index
+            .add(memory(Some(20), "other guild", vec![0.9, 0.1]))
+            .unwrap();
+        index.add(memory(None, "unscoped", vec![1.0, 0.0])).unwrap();
+        index
+            .add(memory(Some(1), "unrelated", vec![-1.0, 0.0]))
+            .unwrap();
+
+        let texts = |guild_id| {
+            index
+                .nearest(1, guild_id, &[1.0, 0.0], 10)
+                .unwrap()
+                .into_iter()
+                .map(|memory| memory.text.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(texts(Some(10)), vec!["this channel", "this guild"]);
+        assert_eq!(texts(None), vec!["this channel"]);
+    }
+
+    #[test]
+    fn keeps_the_newest_memories() {
+        let mut index = MemoryIndex::new(2);
+        for (i, text) in ["old", "middle", "new"].into_iter().enumerate() {
+            index
+                .add(memory(Some(1), text, vec![1.0, i as f64 / 10.0]))
+                .unwrap();
+        }
+        index.keep_newest(2).unwrap();
+
+        let texts: Vec<_> = index
+            .nearest(1, None, &[1.0, 0.0], 10)
+            .unwrap()
+            .into_iter()
+            .map(|memory| memory.text.as_str())
+            .collect();
+        assert_eq!(texts, vec!["middle", "new"]);
+    }
+}
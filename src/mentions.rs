@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serenity::{
+    builder::{CreateAllowedMentions, ParseValue},
+    model::prelude::{GuildId, RoleId, User, UserId},
+    prelude::Context,
+};
+
+use crate::{
+    config::{config, MentionsConfig},
+    users,
+};
+
+// Roles, channels and emoji change rarely, and there is no event handling for
+// them, so they are fetched again once they are this old.
+const GUILD_NAMES_TTL: Duration = Duration::from_secs(10 * 60);
+
+struct GuildNames {
+    roles: HashMap<u64, String>,
+    channels: HashMap<u64, String>,
+    // Emoji are keyed by name, with their id and whether they are animated.
+    emoji: HashMap<String, (u64, bool)>,
+}
+
+lazy_static! {
+    // <@id>, <@!id>, <@&id>, <#id>, <:name:id> and <a:name:id>.
+    static ref MARKUP: Regex = Regex::new(r"<(@!?|@&|#)(\d+)>|<a?:(\w+):(\d+)>").unwrap();
+    static ref GUILDS: RwLock<HashMap<u64, (Instant, Arc<GuildNames>)>> =
+        RwLock::new(HashMap::new());
+}
+
+// Everything mentions can refer to in one guild, for turning Discord's markup
+// into names the model can read and back.
+pub struct Names {
+    display_names: HashMap<u64, String>,
+    guild: Option<Arc<GuildNames>>,
+    // Names after each sigil with the markup they turn into, longest first.
+    at: Vec<(String, String)>,
+    hash: Vec<(String, String)>,
+    colon: Vec<(String, String)>,
+}
+
+// Who a reply may ping. Everything else in it is shown without pinging
+// anyone.
+#[derive(Default)]
+pub struct Pings {
+    users: Vec<u64>,
+    roles: Vec<u64>,
+    everyone: bool,
+    pub suppressed: Vec<String>,
+}
+
+impl Pings {
+    pub fn apply<'a>(
+        &self,
+        allowed: &'a mut CreateAllowedMentions,
+    ) -> &'a mut CreateAllowedMentions {
+        allowed.empty_parse();
+        if self.everyone {
+            allowed.parse(ParseValue::Everyone);
+        }
+        allowed
+            .users(self.users.iter().map(|id| UserId(*id)))
+            .roles(self.roles.iter().map(|id| RoleId(*id)))
+            .replied_user(true)
+    }
+}
+
+async fn fetch_guild(ctx: &Context, guild_id: GuildId) -> Result<GuildNames> {
+    Ok(GuildNames {
+        roles: guild_id
+            .roles(&ctx.http)
+            .await?
+            .into_iter()
+            .map(|(id, role)| (id.0, role.name))
+            .collect(),
+        channels: guild_id
+            .channels(&ctx.http)
+            .await?
+            .into_iter()
+            .map(|(id, channel)| (id.0, channel.name))
+            .collect(),
+        emoji: guild_id
+            .emojis(&ctx.http)
+            .await?
+            .into_iter()
+            .map(|emoji| (emoji.name, (emoji.id.0, emoji.animated)))
+            .collect(),
+    })
+}
+
+async fn guild_names(ctx: &Context, guild_id: GuildId) -> Result<Arc<GuildNames>> {
+    let cached = GUILDS
+        .read()
+        .unwrap()
+        .get(&guild_id.0)
+        .filter(|(fetched, _)| fetched.elapsed() < GUILD_NAMES_TTL)
+        .map(|(_, names)| names.clone());
+    if let Some(names) = cached {
+        return Ok(names);
+    }
+
+    let names = Arc::new(fetch_guild(ctx, guild_id).await?);
+    GUILDS
+        .write()
+        .unwrap()
+        .insert(guild_id.0, (Instant::now(), names.clone()));
+    Ok(names)
+}
+
+pub async fn names(ctx: &Context, guild_id: Option<GuildId>) -> Result<Names> {
+    let guild = match guild_id {
+        Some(guild_id) => Some(guild_names(ctx, guild_id).await?),
+        None => None,
+    };
+
+    Ok(Names::new(
+        users::all_names(ctx, guild_id).await?,
+        users::names_by_id(ctx, guild_id).await?,
+        guild,
+    ))
+}
+
+impl Names {
+    fn new(
+        user_names: Vec<(String, u64)>,
+        display_names: HashMap<u64, String>,
+        guild: Option<Arc<GuildNames>>,
+    ) -> Self {
+        let mut at: Vec<(String, String)> = user_names
+            .into_iter()
+            .map(|(name, id)| (name, format!("<@{id}>")))
+            .collect();
+        let mut hash = Vec::new();
+        let mut colon = Vec::new();
+        if let Some(guild) = guild.as_deref() {
+            at.extend(
+                guild
+                    .roles
+                    .iter()
+                    // @everyone would ping the whole server.
+                    .filter(|(_, name)| name.as_str() != "@everyone")
+                    .map(|(id, name)| (name.clone(), format!("<@&{id}>"))),
+            );
+            hash.extend(
+                guild
+                    .channels
+                    .iter()
+                    .map(|(id, name)| (name.clone(), format!("<#{id}>"))),
+            );
+            colon.extend(guild.emoji.iter().map(|(name, (id, animated))| {
+                let prefix = if *animated { "a" } else { "" };
+                (format!("{name}:"), format!("<{prefix}:{name}:{id}>"))
+            }));
+        }
+        for names in [&mut at, &mut hash, &mut colon] {
+            names.retain(|(name, _)| !name.is_empty());
+            names.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        }
+
+        Names {
+            display_names,
+            guild,
+            at,
+            hash,
+            colon,
+        }
+    }
+
+    // Replaces mentions and custom emoji with @name, @role, #channel and
+    // :emoji:. Users who aren't known members, like bots, are looked up in
+    // the users the message mentions. Anything unknown is left as it is.
+    pub fn readable(&self, text: &str, mentioned: &[User]) -> String {
+        MARKUP
+            .replace_all(text, |captures: &Captures| {
+                let original = captures[0].to_string();
+                if let Some(emoji) = captures.get(3) {
+                    return format!(":{}:", emoji.as_str());
+                }
+
+                let Ok(id) = captures[2].parse::<u64>() else {
+                    return original;
+                };
+                let guild = self.guild.as_deref();
+                let name = match &captures[1] {
+                    "#" => guild.and_then(|guild| guild.channels.get(&id)).cloned(),
+                    "@&" => guild.and_then(|guild| guild.roles.get(&id)).cloned(),
+                    _ => self.display_names.get(&id).cloned().or_else(|| {
+                        mentioned
+                            .iter()
+                            .find(|user| user.id.0 == id)
+                            .map(|user| user.name.clone())
+                    }),
+                };
+
+                match name {
+                    Some(name) if &captures[1] == "#" => format!("#{name}"),
+                    Some(name) => format!("@{name}"),
+                    None => original,
+                }
+            })
+            .into_owned()
+    }
+
+    // Decides which of the mentions in a reply, already turned into markup,
+    // may ping. Users only get pinged if the model was told about them, and
+    // @everyone, @here and roles only in the channels which allow it.
+    pub fn pings(&self, text: &str, channel_id: u64) -> Pings {
+        self.allowed_pings(text, channel_id, &config().mentions)
+    }
+
+    fn allowed_pings(&self, text: &str, channel_id: u64, mentions: &MentionsConfig) -> Pings {
+        let mut pings = Pings::default();
+
+        for captures in MARKUP.captures_iter(text) {
+            let (Some(kind), Some(id)) = (captures.get(1), captures.get(2)) else {
+                continue;
+            };
+            let Ok(id) = id.as_str().parse::<u64>() else {
+                continue;
+            };
+            match kind.as_str() {
+                "#" => {}
+                "@&" if mentions.role_channels.contains(&channel_id) => pings.roles.push(id),
+                _ if kind.as_str() != "@&"
+                    && mentions.users
+                    && self.display_names.contains_key(&id) =>
+                {
+                    pings.users.push(id)
+                }
+                _ => pings.suppressed.push(captures[0].to_string()),
+            }
+        }
+
+        for everyone in ["@everyone", "@here"] {
+            if !text.contains(everyone) {
+                continue;
+            }
+            if mentions.everyone_channels.contains(&channel_id) {
+                pings.everyone = true;
+            } else {
+                pings.suppressed.push(everyone.to_string());
+            }
+        }
+
+        for ids in [&mut pings.users, &mut pings.roles] {
+            ids.sort_unstable();
+            ids.dedup();
+        }
+        pings.suppressed.sort_unstable();
+        pings.suppressed.dedup();
+        pings
+    }
+
+    // Turns @name, @role, #channel and :emoji: back into Discord's markup.
+    pub fn discord(&self, text: &str) -> String {
+        let text = link(text, '@', &self.at);
+        let text = link(&text, '#', &self.hash);
+        link(&text, ':', &self.colon)
+    }
+}
+
+// Replaces sigil followed by one of the names with its markup. Names have to
+// stand on their own, so "@Kay" doesn't match inside "@Kayla" and the "@" in
+// an email address is left alone. Names are tried in order, so longer ones
+// have to come first.
+fn link(text: &str, sigil: char, names: &[(String, String)]) -> String {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(sigil) {
+        let (before, after) = rest.split_at(index);
+        output.push_str(before);
+        let after = &after[sigil.len_utf8()..];
+
+        // Markup which is already there, like <:emoji:id>, is left alone.
+        let starts_word = !output
+            .chars()
+            .next_back()
+            .is_some_and(|c| is_word(c) || c == '<');
+        let found = names.iter().find(|(name, _)| {
+            let Some(candidate) = after.get(..name.len()) else {
+                return false;
+            };
+            let ends_word = !after[name.len()..].chars().next().is_some_and(is_word);
+            candidate.eq_ignore_ascii_case(name) && (ends_word || name.ends_with(':'))
+        });
+
+        match found {
+            Some((name, markup)) if starts_word => {
+                output.push_str(markup);
+                rest = &after[name.len()..];
+            }
+            _ => {
+                output.push(sigil);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Names {
+        let guild = GuildNames {
+            roles: HashMap::from([(10, "mods".to_string()), (11, "@everyone".to_string())]),
+            channels: HashMap::from([
+                (20, "general".to_string()),
+                (21, "general-chat".to_string()),
+            ]),
+            emoji: HashMap::from([
+                ("wave".to_string(), (30, false)),
+                ("party".to_string(), (31, true)),
+            ]),
+        };
+        Names::new(
+            vec![
+                ("Kay".to_string(), 1),
+                ("Kayla".to_string(), 2),
+                ("kay_".to_string(), 3),
+            ],
+            HashMap::from([
+                (1, "Kay".to_string()),
+                (2, "Kayla".to_string()),
+                (3, "kay_".to_string()),
+            ]),
+            Some(Arc::new(guild)),
+        )
+    }
+
+    #[test]
+    fn markup_is_made_readable() {
+        assert_eq!(
+            names().readable("<@1> <@!2> <@&10> <#20> <:wave:30> <a:party:31>", &[]),
+            "@Kay @Kayla @mods #general :wave: :party:"
+        );
+    }
+
+    #[test]
+    fn unknown_markup_is_kept() {
+        assert_eq!(
+            names().readable("<@9> <@&19> <#29>", &[]),
+            "<@9> <@&19> <#29>"
+        );
+        let names = Names::new(Vec::new(), HashMap::new(), None);
+        assert_eq!(names.readable("<@&10> <#20>", &[]), "<@&10> <#20>");
+    }
+
+    #[test]
+    fn mentioned_users_are_used_for_unknown_members() {
+        let mut bot = User::default();
+        bot.id = UserId(9);
+        bot.name = "Helper".to_string();
+        assert_eq!(names().readable("<@9> <@1>", &[bot]), "@Helper @Kay");
+    }
+
+    #[test]
+    fn names_are_linked() {
+        assert_eq!(
+            names().discord("@Kay @mods #general :wave: :party:"),
+            "<@1> <@&10> <#20> <:wave:30> <a:party:31>"
+        );
+        assert_eq!(names().discord("hi @kay"), "hi <@1>");
+    }
+
+    #[test]
+    fn the_longest_colliding_name_wins() {
+        assert_eq!(
+            names().discord("@Kayla @Kay @kay_ #general-chat #general"),
+            "<@2> <@1> <@3> <#21> <#20>"
+        );
+    }
+
+    #[test]
+    fn names_must_stand_on_their_own() {
+        assert_eq!(names().discord("@Kaylee"), "@Kaylee");
+        assert_eq!(names().discord("kay@Kay.com"), "kay@Kay.com");
+        assert_eq!(
+            names().discord("@nobody #nowhere :nothing:"),
+            "@nobody #nowhere :nothing:"
+        );
+    }
+
+    #[test]
+    fn existing_markup_and_everyone_are_left_alone() {
+        assert_eq!(names().discord("<:wave:30> <@1>"), "<:wave:30> <@1>");
+        assert_eq!(names().discord("@everyone"), "@everyone");
+    }
+
+    fn settings() -> MentionsConfig {
+        MentionsConfig {
+            users: true,
+            everyone_channels: vec![100],
+            role_channels: vec![100, 101],
+        }
+    }
+
+    #[test]
+    fn known_users_are_pinged() {
+        let pings = names().allowed_pings("<@1> <@!2> <@1> <@9>", 200, &settings());
+        assert_eq!(pings.users, vec![1, 2]);
+        assert_eq!(pings.suppressed, vec!["<@9>"]);
+    }
+
+    #[test]
+    fn users_are_not_pinged_when_turned_off() {
+        let settings = MentionsConfig {
+            users: false,
+            ..settings()
+        };
+        let pings = names().allowed_pings("<@1>", 200, &settings);
+        assert!(pings.users.is_empty());
+        assert_eq!(pings.suppressed, vec!["<@1>"]);
+    }
+
+    #[test]
+    fn roles_are_only_pinged_where_allowed() {
+        let pings = names().allowed_pings("<@&10>", 101, &settings());
+        assert_eq!(pings.roles, vec![10]);
+        assert!(pings.suppressed.is_empty());
+
+        let pings = names().allowed_pings("<@&10> <@&1>", 200, &settings());
+        assert!(pings.roles.is_empty());
+        assert!(pings.users.is_empty());
+        assert_eq!(pings.suppressed, vec!["<@&10>", "<@&1>"]);
+    }
+
+    #[test]
+    fn everyone_is_only_pinged_where_allowed() {
+        let pings = names().allowed_pings("@everyone and @here", 100, &settings());
+        assert!(pings.everyone);
+        assert!(pings.suppressed.is_empty());
+
+        let pings = names().allowed_pings("@everyone and @here", 101, &settings());
+        assert!(!pings.everyone);
+        assert_eq!(pings.suppressed, vec!["@everyone", "@here"]);
+    }
+
+    #[test]
+    fn channels_and_emoji_never_ping() {
+        let pings = names().allowed_pings("<#20> <:wave:30>", 200, &settings());
+        assert!(pings.users.is_empty() && pings.roles.is_empty() && !pings.everyone);
+        assert!(pings.suppressed.is_empty());
+    }
+
+    #[test]
+    fn readable_and_discord_round_trip() {
+        let names = names();
+        let text = "<@2>, ask <@&10> in <#21> <:wave:30>";
+        assert_eq!(names.discord(&names.readable(text, &[])), text);
+    }
+}
@@ -0,0 +1,361 @@
+mod compatible;
+mod openai;
+mod retry;
+
+use std::{borrow::Cow, fmt, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serenity::async_trait;
+
+use crate::config::{config, ProviderKind};
+pub use compatible::OpenAiCompatibleProvider;
+pub use openai::OpenAiProvider;
+pub use retry::RetryingProvider;
+
+// Messages use the same shape as the chat completions api so that they can be
+// stored and sent as is.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+    // Results of the deprecated functions api. Only found in old histories,
+    // and never sent.
+    Function,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+// Plain text, or a list of parts for messages which include images.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImageUrl {
+    pub url: String,
+    // "low", "high" or "auto".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Content {
+    // The text of the content, with parts joined by newlines.
+    pub fn text(&self) -> Cow<str> {
+        match self {
+            Content::Text(text) => Cow::Borrowed(text),
+            Content::Parts(parts) => Cow::Owned(
+                parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text.as_str()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    pub fn image_count(&self) -> usize {
+        match self {
+            Content::Text(_) => 0,
+            Content::Parts(parts) => parts
+                .iter()
+                .filter(|part| matches!(part, ContentPart::ImageUrl { .. }))
+                .count(),
+        }
+    }
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        ContentPart::Text { text: text.into() }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<Vec<ContentPart>> for Content {
+    // A single text part is sent as plain text, which every model accepts.
+    fn from(mut parts: Vec<ContentPart>) -> Self {
+        match parts.as_mut_slice() {
+            [ContentPart::Text { text }] => Content::Text(std::mem::take(text)),
+            _ => Content::Parts(parts),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: Option<Content>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    // Set on tool results to match them with the call they answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::System,
+            content: Some(Content::Text(content.into())),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Assistant,
+            content: Some(Content::Text(content.into())),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn user(name: Option<String>, content: impl Into<Content>) -> Self {
+        Self {
+            role: Role::User,
+            content: Some(content.into()),
+            name,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: Role::Tool,
+            content: Some(Content::Text(content.into())),
+            name: None,
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id.into()),
+        }
+    }
+
+    pub fn text(&self) -> Option<Cow<str>> {
+        self.content.as_ref().map(Content::text)
+    }
+
+    // Images are replaced by the placeholders next to them, for models which
+    // can't see them or images which were already looked at.
+    pub fn without_images(mut self) -> Self {
+        if let Some(Content::Parts(parts)) = self.content.take() {
+            let parts: Vec<ContentPart> = parts
+                .into_iter()
+                .filter(|part| matches!(part, ContentPart::Text { .. }))
+                .collect();
+            self.content = Some(Content::Text(Content::Parts(parts).text().into_owned()));
+        }
+        self
+    }
+
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        self.tool_calls.as_deref().unwrap_or_default()
+    }
+
+    // Messages from the functions api can't be mixed with tools, so they're
+    // left out of requests. That covers function results and the assistant
+    // messages which only held a function call.
+    pub fn is_legacy(&self) -> bool {
+        match self.role {
+            Role::Function => true,
+            Role::Assistant => self.content.is_none() && self.tool_calls().is_empty(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ToolDefinition {
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+impl From<FunctionDefinition> for ToolDefinition {
+    fn from(function: FunctionDefinition) -> Self {
+        Self {
+            kind: function_type(),
+            function,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+    // "auto", "none" or {"type": "function", "function": {"name": ...}} to
+    // force a specific tool.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<Value>,
+}
+
+impl ChatRequest {
+    pub fn new(model: impl Into<String>, messages: Vec<ChatMessage>) -> Self {
+        Self {
+            model: model.into(),
+            messages,
+            tools: Vec::new(),
+            tool_choice: None,
+            stream: false,
+            stream_options: None,
+        }
+    }
+
+    // The same request for another model, leaving out images if it can't
+    // see them.
+    pub fn for_model(&self, model: &str) -> Self {
+        let mut request = ChatRequest {
+            model: model.to_string(),
+            ..self.clone()
+        };
+        if !config().supports_vision(model) {
+            request.messages = request
+                .messages
+                .into_iter()
+                .map(ChatMessage::without_images)
+                .collect();
+        }
+        request
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ChatResponse {
+    // The model which answered, which may be a fallback.
+    pub model: String,
+    pub message: ChatMessage,
+    pub usage: Option<Usage>,
+}
+
+// An api which answered with an error status.
+#[derive(Debug)]
+pub struct StatusError {
+    pub url: String,
+    pub status: u16,
+    pub body: String,
+    // How long the api asked us to wait before trying again.
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} returned {}: {}", self.url, self.status, self.body)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// Gets each piece of the reply's content as it arrives.
+pub type DeltaSink<'a> = &'a (dyn Fn(&str) + Send + Sync);
+
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatResponse>;
+
+    // Like complete, but hands over the content while it is being generated.
+    // Providers which can't stream send it all at once at the end.
+    async fn stream(&self, request: &ChatRequest, on_delta: DeltaSink<'_>) -> Result<ChatResponse> {
+        let response = self.complete(request).await?;
+        if let Some(content) = response.message.text() {
+            on_delta(&content);
+        }
+        Ok(response)
+    }
+}
+
+lazy_static! {
+    static ref PROVIDER: Arc<dyn ChatProvider> =
+        open().expect("Could not create the chat provider");
+}
+
+fn open() -> Result<Arc<dyn ChatProvider>> {
+    let config = config();
+    let provider: Arc<dyn ChatProvider> = match config.provider.kind {
+        ProviderKind::OpenAi => Arc::new(OpenAiProvider::new(&config.provider)?),
+        ProviderKind::Compatible => Arc::new(OpenAiCompatibleProvider::new(&config.provider)?),
+    };
+    Ok(Arc::new(RetryingProvider::new(provider)))
+}
+
+// A client for the other endpoints of the configured api, such as embeddings.
+pub fn http_client() -> Result<OpenAiCompatibleProvider> {
+    let config = config();
+    match config.provider.kind {
+        ProviderKind::OpenAi => OpenAiProvider::client(&config.provider),
+        ProviderKind::Compatible => OpenAiCompatibleProvider::new(&config.provider),
+    }
+}
+
+pub fn init() {
+    lazy_static::initialize(&PROVIDER);
+}
+
+pub fn provider() -> Arc<dyn ChatProvider> {
+    PROVIDER.clone()
+}
@@ -0,0 +1,264 @@
+use std::{env, time::Duration};
+
+use anyhow::{Context, Result};
+use reqwest::{header::RETRY_AFTER, Client, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use serenity::{async_trait, futures::StreamExt};
+
+use super::{
+    ChatMessage, ChatProvider, ChatRequest, ChatResponse, Content, DeltaSink, FunctionCall, Role,
+    StatusError, ToolCall, Usage,
+};
+use crate::config::ProviderConfig;
+
+// Talks to anything implementing the OpenAI chat completions api, such as a
+// llama.cpp or Ollama server.
+pub struct OpenAiCompatibleProvider {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ChatMessage,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+// Streamed replies arrive as server sent events, each holding a piece of the
+// message.
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    delta: Delta,
+}
+
+#[derive(Deserialize, Default)]
+struct Delta {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+// Tool calls are streamed in fragments, tied together by their index.
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionDelta {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f64>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+impl OpenAiCompatibleProvider {
+    pub fn new(settings: &ProviderConfig) -> Result<Self> {
+        let base_url = settings
+            .base_url
+            .clone()
+            .context("provider.base_url is required for compatible providers")?;
+        // Local servers usually don't need a key.
+        let api_key = match settings.api_key_env.as_ref() {
+            Some(name) => {
+                Some(env::var(name).with_context(|| format!("Expected an api key in {name}"))?)
+            }
+            None => None,
+        };
+        Self::with_base_url(base_url, api_key)
+    }
+
+    pub fn with_base_url(base_url: impl Into<String>, api_key: Option<String>) -> Result<Self> {
+        Ok(Self {
+            client: Client::builder().build()?,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key,
+        })
+    }
+
+    async fn post<T: Serialize, R: DeserializeOwned>(&self, path: &str, body: &T) -> Result<R> {
+        let (url, response) = self.send(path, body).await?;
+        response
+            .json()
+            .await
+            .with_context(|| format!("Could not parse the response from {url}"))
+    }
+
+    async fn send<T: Serialize>(&self, path: &str, body: &T) -> Result<(String, Response)> {
+        let url = format!("{}/{}", self.base_url, path);
+        let mut request = self.client.post(&url).json(body);
+        if let Some(api_key) = self.api_key.as_ref() {
+            request = request.bearer_auth(api_key);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Could not reach {url}"))?;
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs);
+            let body = response.text().await.unwrap_or_default();
+            return Err(StatusError {
+                url,
+                status: status.as_u16(),
+                body,
+                retry_after,
+            }
+            .into());
+        }
+
+        Ok((url, response))
+    }
+
+    pub async fn embed(&self, model: &str, input: &str) -> Result<Vec<f64>> {
+        let response: EmbeddingResponse = self
+            .post("embeddings", &EmbeddingRequest { model, input })
+            .await?;
+        response
+            .data
+            .into_iter()
+            .next()
+            .map(|data| data.embedding)
+            .context("No embedding was returned")
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiCompatibleProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        let response: CompletionResponse = self.post("chat/completions", request).await?;
+        let message = response
+            .choices
+            .into_iter()
+            .next()
+            .context("No choices were returned")?
+            .message;
+
+        Ok(ChatResponse {
+            model: request.model.clone(),
+            message,
+            usage: response.usage,
+        })
+    }
+
+    async fn stream(&self, request: &ChatRequest, on_delta: DeltaSink<'_>) -> Result<ChatResponse> {
+        let request = ChatRequest {
+            stream: true,
+            stream_options: Some(json!({ "include_usage": true })),
+            ..request.clone()
+        };
+        let (url, response) = self.send("chat/completions", &request).await?;
+
+        let mut content = String::new();
+        let mut tool_calls: Vec<ToolCall> = Vec::new();
+        let mut usage = None;
+
+        // Events can be split across chunks, so bytes are buffered until a
+        // whole line is in.
+        let mut buffer = Vec::new();
+        let mut bytes = response.bytes_stream();
+        'events: while let Some(chunk) = bytes.next().await {
+            buffer
+                .extend_from_slice(&chunk.with_context(|| format!("Lost the stream from {url}"))?);
+
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    break 'events;
+                }
+
+                let chunk: StreamChunk = serde_json::from_str(data)
+                    .with_context(|| format!("Could not parse a streamed chunk from {url}"))?;
+                usage = chunk.usage.or(usage);
+                for choice in chunk.choices {
+                    if let Some(delta) = choice.delta.content {
+                        on_delta(&delta);
+                        content.push_str(&delta);
+                    }
+                    for delta in choice.delta.tool_calls {
+                        merge_tool_call(&mut tool_calls, delta);
+                    }
+                }
+            }
+        }
+
+        Ok(ChatResponse {
+            model: request.model.clone(),
+            message: ChatMessage {
+                role: Role::Assistant,
+                content: (!content.is_empty()).then_some(Content::Text(content)),
+                name: None,
+                tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                tool_call_id: None,
+            },
+            usage,
+        })
+    }
+}
+
+fn merge_tool_call(tool_calls: &mut Vec<ToolCall>, delta: ToolCallDelta) {
+    while tool_calls.len() <= delta.index {
+        tool_calls.push(ToolCall {
+            id: String::new(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+    }
+
+    let tool_call = &mut tool_calls[delta.index];
+    if let Some(id) = delta.id {
+        tool_call.id = id;
+    }
+    if let Some(function) = delta.function {
+        if let Some(name) = function.name {
+            tool_call.function.name.push_str(&name);
+        }
+        if let Some(arguments) = function.arguments {
+            tool_call.function.arguments.push_str(&arguments);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::env;
+
+use anyhow::{Context, Result};
+use serenity::async_trait;
+
+use super::{ChatProvider, ChatRequest, ChatResponse, DeltaSink, OpenAiCompatibleProvider};
+use crate::config::ProviderConfig;
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_KEY_VARIABLE: &str = "OPENAI_KEY";
+
+pub struct OpenAiProvider {
+    client: OpenAiCompatibleProvider,
+}
+
+impl OpenAiProvider {
+    pub fn new(settings: &ProviderConfig) -> Result<Self> {
+        Ok(Self {
+            client: Self::client(settings)?,
+        })
+    }
+
+    // Unlike compatible providers, OpenAI always needs a key. The base url can
+    // still be overridden to go through a proxy.
+    pub fn client(settings: &ProviderConfig) -> Result<OpenAiCompatibleProvider> {
+        let key_variable = settings
+            .api_key_env
+            .as_deref()
+            .unwrap_or(DEFAULT_KEY_VARIABLE);
+        let api_key = env::var(key_variable)
+            .with_context(|| format!("Expected an OpenAI key in {key_variable}"))?;
+        let base_url = settings.base_url.as_deref().unwrap_or(OPENAI_BASE_URL);
+
+        OpenAiCompatibleProvider::with_base_url(base_url, Some(api_key))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        self.client.complete(request).await
+    }
+
+    async fn stream(&self, request: &ChatRequest, on_delta: DeltaSink<'_>) -> Result<ChatResponse> {
+        self.client.stream(request, on_delta).await
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    fmt, iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Error, Result};
+use serenity::async_trait;
+
+use super::{ChatProvider, ChatRequest, ChatResponse, DeltaSink, StatusError};
+use crate::config::{config, RetryConfig};
+
+// A model which didn't answer within the per request timeout.
+#[derive(Debug)]
+struct TimeoutError {
+    model: String,
+    timeout: Duration,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} did not answer within {:?}", self.model, self.timeout)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+// Retries failed completions with exponential backoff, then moves on to the
+// configured fallback models. Settings are read on every request so that
+// they follow config reloads.
+pub struct RetryingProvider {
+    inner: Arc<dyn ChatProvider>,
+}
+
+impl RetryingProvider {
+    pub fn new(inner: Arc<dyn ChatProvider>) -> Self {
+        Self { inner }
+    }
+
+    async fn attempt(
+        &self,
+        request: &ChatRequest,
+        timeout: Duration,
+        on_delta: Option<DeltaSink<'_>>,
+        started: &AtomicBool,
+    ) -> Result<ChatResponse> {
+        let timed_out = || TimeoutError {
+            model: request.model.clone(),
+            timeout,
+        };
+        let Some(on_delta) = on_delta else {
+            return tokio::time::timeout(timeout, self.inner.complete(request))
+                .await
+                .map_err(|_| timed_out())?;
+        };
+
+        let sink = |delta: &str| {
+            started.store(true, Ordering::Relaxed);
+            on_delta(delta);
+        };
+        let stream = self.inner.stream(request, &sink);
+        tokio::pin!(stream);
+
+        // The timeout only covers the wait for the reply to start. Streaming
+        // a long reply can take a while.
+        tokio::select! {
+            response = &mut stream => response,
+            _ = tokio::time::sleep(timeout) => {
+                if started.load(Ordering::Relaxed) {
+                    stream.await
+                } else {
+                    Err(timed_out().into())
+                }
+            }
+        }
+    }
+
+    async fn attempt_with_retries(
+        &self,
+        request: &ChatRequest,
+        settings: &RetryConfig,
+        on_delta: Option<DeltaSink<'_>>,
+        started: &AtomicBool,
+    ) -> Result<ChatResponse> {
+        let timeout = Duration::from_secs(settings.timeout_seconds);
+        let mut backoff = Duration::from_millis(settings.initial_backoff_ms);
+        let mut attempt = 1;
+
+        loop {
+            let error = match self.attempt(request, timeout, on_delta, started).await {
+                Ok(response) => return Ok(response),
+                Err(why) => why,
+            };
+
+            // Part of a streamed reply may already be in front of users, so
+            // starting over would repeat it.
+            let retryable = is_transient(&error) && !started.load(Ordering::Relaxed);
+            if attempt >= settings.attempts || !retryable {
+                return Err(error);
+            }
+
+            let wait = retry_after(&error).unwrap_or_default().max(backoff);
+            println!(
+                "Attempt {} with {} failed, retrying in {:?}: {:#}",
+                attempt, request.model, wait, error
+            );
+            tokio::time::sleep(wait).await;
+
+            backoff = (backoff * 2).min(Duration::from_millis(settings.max_backoff_ms));
+            attempt += 1;
+        }
+    }
+
+    async fn run(
+        &self,
+        request: &ChatRequest,
+        on_delta: Option<DeltaSink<'_>>,
+    ) -> Result<ChatResponse> {
+        let config = config();
+        let fallbacks = config
+            .fallback_models
+            .iter()
+            .filter(|model| **model != request.model);
+
+        let started = AtomicBool::new(false);
+        let mut last_error = None;
+        for model in iter::once(&request.model).chain(fallbacks) {
+            let request = request.for_model(model);
+            match self
+                .attempt_with_retries(&request, &config.retry, on_delta, &started)
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(why) if started.load(Ordering::Relaxed) => return Err(why),
+                Err(why) => {
+                    println!("Completion with {} failed: {:?}", model, why);
+                    last_error = Some(why);
+                }
+            }
+        }
+
+        Err(last_error.expect("There is always at least one model"))
+    }
+}
+
+// Rate limits, server errors, timeouts and dropped connections tend to go
+// away by themselves. Anything else will fail the same way again.
+fn is_transient(error: &Error) -> bool {
+    if error.is::<TimeoutError>() {
+        return true;
+    }
+    if let Some(status) = error.downcast_ref::<StatusError>() {
+        return status.status == 429 || status.status >= 500;
+    }
+    if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+        return error.is_timeout() || error.is_connect() || error.is_request();
+    }
+    false
+}
+
+fn retry_after(error: &Error) -> Option<Duration> {
+    error.downcast_ref::<StatusError>()?.retry_after
+}
+
+#[async_trait]
+impl ChatProvider for RetryingProvider {
+    async fn complete(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        self.run(request, None).await
+    }
+
+    async fn stream(&self, request: &ChatRequest, on_delta: DeltaSink<'_>) -> Result<ChatResponse> {
+        self.run(request, Some(on_delta)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn timeouts_are_retried() {
+        let timeout = Error::new(TimeoutError {
+            model: "gpt-4".to_string(),
+            timeout: Duration::from_secs(30),
+        });
+        assert!(is_transient(&timeout));
+        assert!(is_transient(&timeout.context("While answering")));
+        assert!(!is_transient(&anyhow!("Something else")));
+    }
+}
@@ -0,0 +1,100 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use notify::{Event, RecursiveMode, Watcher};
+use serenity::{http::Http, model::prelude::ChannelId};
+use tokio::sync::mpsc;
+
+use crate::config::{self, config};
+
+// Editors tend to write files in several steps, so changes are collected for
+// a moment before reloading.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+fn directory(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+// Watches the config and identity files and reloads them when they change.
+// Only the first call does anything, since ready fires again on reconnect.
+pub fn watch(http: Arc<Http>) -> Result<()> {
+    if WATCHING.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let files = [config::config_path(), config().identity.clone()];
+    let file_names: HashSet<OsString> = files
+        .iter()
+        .filter_map(|file| file.file_name().map(|name| name.to_owned()))
+        .collect();
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            sender.send(event).ok();
+        }
+    })?;
+
+    // Watch the directories rather than the files themselves so that files
+    // which get replaced instead of written in place are still picked up.
+    let directories: HashSet<PathBuf> = files.iter().map(|file| directory(file)).collect();
+    for directory in directories.iter() {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+
+    tokio::spawn(async move {
+        // The watcher stops when dropped, so it lives as long as this task.
+        let _watcher = watcher;
+
+        while let Some(event) = receiver.recv().await {
+            let relevant = event.paths.iter().any(|path| {
+                path.file_name()
+                    .map(|name| file_names.contains(name))
+                    .unwrap_or_default()
+            });
+            if !relevant || event.kind.is_access() {
+                continue;
+            }
+
+            tokio::time::sleep(DEBOUNCE).await;
+            while receiver.try_recv().is_ok() {}
+
+            let announcement = match config::reload() {
+                Ok(note) => {
+                    println!("Reloaded config and identity");
+                    let mut announcement = "Reloaded config and identity.".to_string();
+                    if let Some(note) = note {
+                        announcement = format!("{announcement} {note}");
+                    }
+                    announcement
+                }
+                Err(why) => {
+                    println!("Error reloading config: {:?}", why);
+                    format!("Kept the previous config and identity. Reloading failed: {why:#}")
+                }
+            };
+
+            if let Some(admin_channel) = config().admin_channel {
+                if let Err(why) = ChannelId(admin_channel).say(&http, announcement).await {
+                    println!("Error announcing reload: {:?}", why);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,393 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as AnyhowContext, Result};
+use serenity::{model::prelude::Message, prelude::Context};
+
+use crate::{
+    config::config,
+    mentions::{self, Names},
+};
+
+// Longest message Discord accepts, in characters.
+pub const MESSAGE_LIMIT: usize = serenity::constants::MESSAGE_CODE_LIMIT;
+
+// Anything longer after the backticks of a code block isn't a language tag.
+const MAX_LANGUAGE_LENGTH: usize = 20;
+
+// Shows a reply while it is being generated. A message is posted as soon as
+// there is text, then edited as more arrives. Edits are throttled to stay
+// clear of Discord's rate limits, and text past the length limit rolls over
+// into a new message.
+pub struct StreamingReply<'a> {
+    ctx: &'a Context,
+    message: &'a Message,
+    names: Names,
+    // Message being edited, and the text it is showing.
+    current: Option<Message>,
+    shown: String,
+    // Text which belongs in the current message.
+    text: String,
+    replied: bool,
+    last_edit: Option<Instant>,
+    // Mentions which were shown without pinging anyone.
+    suppressed: Vec<String>,
+}
+
+impl<'a> StreamingReply<'a> {
+    pub async fn new(ctx: &'a Context, message: &'a Message) -> Result<StreamingReply<'a>> {
+        Ok(Self {
+            ctx,
+            message,
+            names: mentions::names(ctx, message.guild_id).await?,
+            current: None,
+            shown: String::new(),
+            text: String::new(),
+            replied: false,
+            last_edit: None,
+            suppressed: Vec::new(),
+        })
+    }
+
+    pub async fn push(&mut self, delta: &str) -> Result<()> {
+        self.text.push_str(delta);
+
+        while let Some((first, rest)) = split_first(&self.render(), MESSAGE_LIMIT) {
+            self.show(&first).await?;
+
+            self.current = None;
+            self.shown.clear();
+            self.text = rest;
+        }
+
+        let interval = Duration::from_millis(config().streaming.edit_interval_ms);
+        if self
+            .last_edit
+            .map(|last_edit| last_edit.elapsed() >= interval)
+            .unwrap_or(true)
+        {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    // Makes sure everything received so far is shown.
+    pub async fn finish(mut self) -> Result<()> {
+        self.flush().await?;
+        if !self.suppressed.is_empty() {
+            println!(
+                "Not pinging {:?} in {}",
+                self.suppressed, self.message.channel_id.0
+            );
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        let rendered = self.render();
+        // Discord doesn't allow empty messages.
+        if rendered != self.shown && !rendered.trim().is_empty() {
+            self.show(&rendered).await?;
+        }
+        Ok(())
+    }
+
+    async fn show(&mut self, text: &str) -> Result<()> {
+        let pings = self.names.pings(text, self.message.channel_id.0);
+        match self.current.as_mut() {
+            Some(current) => current
+                .edit(self.ctx, |edit| {
+                    edit.content(text)
+                        .allowed_mentions(|allowed| pings.apply(allowed))
+                })
+                .await
+                .context("Failed to edit message")?,
+            None => {
+                let reply = !self.replied;
+                self.current = Some(
+                    self.message
+                        .channel_id
+                        .send_message(self.ctx, |message| {
+                            if reply {
+                                message.reference_message(self.message);
+                            }
+                            message
+                                .content(text)
+                                .allowed_mentions(|allowed| pings.apply(allowed))
+                        })
+                        .await
+                        .context("Failed to send message")?,
+                );
+                self.replied = true;
+            }
+        }
+
+        for mention in pings.suppressed {
+            if !self.suppressed.contains(&mention) {
+                self.suppressed.push(mention);
+            }
+        }
+        self.shown = text.to_string();
+        self.last_edit = Some(Instant::now());
+        Ok(())
+    }
+
+    fn render(&self) -> String {
+        self.names.discord(&self.text)
+    }
+}
+
+// Byte index of the character at the given position.
+fn char_index(text: &str, position: usize) -> usize {
+    text.char_indices()
+        .nth(position)
+        .map(|(index, _)| index)
+        .unwrap_or(text.len())
+}
+
+// The backticks which open or close a code block, if the line is a fence.
+fn fence_marker(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    let length = line.len() - line.trim_start_matches('`').len();
+    (length >= 3).then(|| &line[..length])
+}
+
+// The opening line of the code block still open at the end of the text, and
+// where that line starts.
+fn open_fence(text: &str) -> Option<(String, usize)> {
+    let mut open: Option<(&str, usize)> = None;
+    let mut offset = 0;
+    for line in text.split('\n') {
+        if let Some(marker) = fence_marker(line) {
+            open = match open {
+                // Closing fences are at least as long as the opening one and
+                // have nothing after them.
+                Some((opening, _))
+                    if line.trim() == marker
+                        && fence_marker(opening).map_or(0, str::len) <= marker.len() =>
+                {
+                    None
+                }
+                Some(open) => Some(open),
+                None => Some((line.trim(), offset)),
+            };
+        }
+        offset += line.len() + 1;
+    }
+    open.map(|(opening, offset)| (opening.to_string(), offset))
+}
+
+// What reopens a code block in the next message: the backticks and the
+// language tag, leaving out anything else on the opening line.
+fn reopening(line: &str) -> String {
+    let line = line.trim();
+    let marker = fence_marker(line).unwrap_or("```");
+    let language = line[marker.len()..]
+        .split_whitespace()
+        .next()
+        .filter(|language| {
+            language.len() <= MAX_LANGUAGE_LENGTH
+                && language
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || "+-#._".contains(c))
+        })
+        .unwrap_or_default();
+    format!("{marker}{language}")
+}
+
+// Where to end a message which has to fit in the window, and how many bytes
+// of separator to drop after it. Paragraph breaks are preferred over line
+// breaks, then sentence ends and then spaces, as long as that doesn't leave
+// the message less than half full.
+fn break_point(window: &str) -> (usize, usize) {
+    let half = window.len() / 2;
+    let late = |index: &usize| *index > half;
+
+    if let Some(index) = window.rfind("\n\n").filter(late) {
+        return (index, 2);
+    }
+    if let Some(index) = window.rfind('\n').filter(late) {
+        return (index, 1);
+    }
+    let sentence_end = window
+        .char_indices()
+        .zip(window.chars().skip(1))
+        .filter(|((_, c), next)| matches!(c, '.' | '!' | '?') && *next == ' ')
+        .map(|((index, _), _)| index + 1)
+        .last();
+    if let Some(index) = sentence_end.filter(late) {
+        return (index, 1);
+    }
+    if let Some(index) = window.rfind(' ').filter(late) {
+        return (index, 1);
+    }
+    (window.len(), 0)
+}
+
+// Splits off the first message of a text which is too long for one. Returns
+// that message and the rest of the text, or None if the text already fits. A
+// code block which gets split is closed at the end of the message and
+// reopened, with its language, at the start of the rest.
+pub fn split_first(text: &str, limit: usize) -> Option<(String, String)> {
+    if text.chars().count() <= limit {
+        return None;
+    }
+
+    // Leaves room for closing a code block.
+    let reserve = text
+        .lines()
+        .filter_map(fence_marker)
+        .map(|marker| marker.len() + 1)
+        .max()
+        .unwrap_or_default();
+    let window = &text[..char_index(text, limit.saturating_sub(reserve).max(1))];
+    let (cut, skip) = break_point(window);
+    let first = text[..cut].trim_end();
+    let rest = &text[cut + skip..];
+
+    let (first, rest) = match open_fence(first) {
+        // Nothing of the block would be in this message, so all of it moves
+        // to the next one.
+        Some((opening, offset)) if offset > 0 && first[offset..].trim() == opening => (
+            first[..offset].trim_end().to_string(),
+            text[offset..].to_string(),
+        ),
+        Some((opening, _)) => (
+            format!("{first}\n{}", fence_marker(&opening).unwrap_or("```")),
+            format!("{}\n{rest}", reopening(&opening)),
+        ),
+        None => (first.to_string(), rest.to_string()),
+    };
+
+    // Reopening a block can undo the progress when there is nothing to break
+    // on, like a fence line longer than a message. That gets cut wherever it
+    // has to be.
+    if rest.len() >= text.len() {
+        let split = char_index(text, limit.max(1));
+        return Some((text[..split].to_string(), text[split..].to_string()));
+    }
+    Some((first, rest))
+}
+
+// Splits text into messages which each fit within the limit.
+pub fn split(text: &str, limit: usize) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut rest = text.to_string();
+    while let Some((first, remaining)) = split_first(&rest, limit) {
+        if !first.is_empty() {
+            messages.push(first);
+        }
+        rest = remaining;
+    }
+    if !rest.trim().is_empty() {
+        messages.push(rest);
+    }
+    messages
+}
+
+// Whether most of the text is inside code blocks.
+pub fn is_mostly_code(text: &str) -> bool {
+    let mut in_code = false;
+    let mut code = 0;
+    for line in text.lines() {
+        if fence_marker(line).is_some() {
+            in_code = !in_code;
+        } else if in_code {
+            code += line.len();
+        }
+    }
+    code * 2 > text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_fits(messages: &[String], limit: usize) {
+        for message in messages {
+            assert!(
+                message.chars().count() <= limit,
+                "{message:?} is longer than {limit}"
+            );
+        }
+    }
+
+    fn balanced(message: &str) -> bool {
+        message
+            .lines()
+            .filter(|line| fence_marker(line).is_some())
+            .count()
+            % 2
+            == 0
+    }
+
+    #[test]
+    fn text_which_fits_is_left_alone() {
+        assert_eq!(split_first("hello", 10), None);
+        assert_eq!(split("hello", 10), vec!["hello"]);
+    }
+
+    #[test]
+    fn splits_between_multi_byte_characters() {
+        let text = "😀".repeat(30);
+        let messages = split(&text, 7);
+        assert_fits(&messages, 7);
+        assert_eq!(messages.concat(), text);
+    }
+
+    #[test]
+    fn prefers_paragraph_breaks() {
+        let text = format!("{}\n\n{}", "a".repeat(30), "b".repeat(30));
+        let (first, rest) = split_first(&text, 40).unwrap();
+        assert_eq!(first, "a".repeat(30));
+        assert_eq!(rest, "b".repeat(30));
+    }
+
+    #[test]
+    fn reopens_split_code_blocks_with_their_language() {
+        let code = "let x = 1;\n".repeat(20);
+        let text = format!("Here you go:\n\n```rust\n{code}```\nDone.");
+        let messages = split(&text, 100);
+        assert_fits(&messages, 100);
+        assert!(messages.len() > 2);
+        for message in &messages[1..] {
+            assert!(message.starts_with("```rust\n"), "{message:?}");
+        }
+        for message in &messages {
+            assert!(balanced(message), "{message:?}");
+        }
+        assert!(messages.last().unwrap().ends_with("```\nDone."));
+    }
+
+    #[test]
+    fn fence_lines_longer_than_a_message_are_split() {
+        for text in [
+            format!("```{}", "word ".repeat(600)),
+            format!("```{}", "a".repeat(3000)),
+        ] {
+            let (_, rest) = split_first(&text, 2000).unwrap();
+            assert!(rest.len() < text.len());
+            let messages = split(&text, 2000);
+            assert_fits(&messages, 2000);
+            assert!(messages.len() <= 3, "{}", messages.len());
+        }
+    }
+
+    #[test]
+    fn reopening_keeps_only_the_language_tag() {
+        assert_eq!(reopening("```rust"), "```rust");
+        assert_eq!(reopening("  ````py title=example"), "````py");
+        assert_eq!(reopening(&format!("```{}", "a".repeat(100))), "```");
+    }
+
+    #[test]
+    fn detects_mostly_code() {
+        assert!(is_mostly_code(
+            "Like this:\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```"
+        ));
+        assert!(!is_mostly_code(
+            "Plenty of prose around a tiny bit of code, which is not much.\n```\nx\n```"
+        ));
+        assert!(!is_mostly_code("No code at all."));
+    }
+}
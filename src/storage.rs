@@ -0,0 +1,235 @@
+mod json;
+mod sqlite;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{config::config, conversation::Conversation, memory::Memory, provider::ChatMessage};
+use anyhow::Result;
+pub use json::{read_json, JsonStorage};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+pub use sqlite::SqliteStorage;
+
+// Tokens spent and what they cost, totalled per day, user, channel and model.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsageRecord {
+    // UTC date as YYYY-MM-DD.
+    pub day: String,
+    // 0 for completions which aren't for anyone in particular, like summaries.
+    pub user_id: u64,
+    pub channel_id: u64,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // In dollars, at the prices when the tokens were used.
+    pub cost: f64,
+}
+
+impl UsageRecord {
+    fn same_totals(&self, other: &UsageRecord) -> bool {
+        self.day == other.day
+            && self.user_id == other.user_id
+            && self.channel_id == other.channel_id
+            && self.model == other.model
+    }
+}
+
+pub trait Storage: Send + Sync {
+    fn messages(&self, channel_id: u64) -> Result<Vec<ChatMessage>>;
+    fn push_message(&self, channel_id: u64, message: &ChatMessage) -> Result<()>;
+    fn remove_oldest_messages(&self, channel_id: u64, count: usize) -> Result<()>;
+    fn clear_messages(&self, channel_id: u64) -> Result<()>;
+
+    fn opinion(&self, user_id: u64) -> Result<Option<u8>>;
+    // Applies the update to the current opinion atomically and returns the
+    // new value.
+    fn update_opinion(&self, user_id: u64, update: &dyn Fn(Option<u8>) -> u8) -> Result<u8>;
+
+    fn summary(&self, channel_id: u64) -> Result<Option<String>>;
+    fn set_summary(&self, channel_id: u64, summary: Option<&str>) -> Result<()>;
+
+    // Adds to the totals for the record's day, user, channel and model.
+    fn record_usage(&self, usage: &UsageRecord) -> Result<()>;
+    // Totals for the given day and every day after it.
+    fn usage_since(&self, day: &str) -> Result<Vec<UsageRecord>>;
+
+    // Applies the update to the channel's conversation atomically and returns
+    // the new state.
+    fn update_conversation(
+        &self,
+        channel_id: u64,
+        update: &dyn Fn(Option<Conversation>) -> Conversation,
+    ) -> Result<Conversation>;
+
+    // Oldest first.
+    fn memories(&self) -> Result<Vec<Memory>>;
+    // Adds the memories and forgets the oldest ones past the limit.
+    fn add_memories(&self, memories: &[Memory], limit: usize) -> Result<()>;
+}
+
+lazy_static! {
+    static ref STORAGE: Box<dyn Storage> = open().expect("Could not open storage");
+}
+
+// Uses the configured SQLite database if there is one, and the json files in
+// the working directory otherwise.
+fn open() -> Result<Box<dyn Storage>> {
+    match config().storage.database.as_ref() {
+        Some(path) => Ok(Box::new(SqliteStorage::open(path, ".")?)),
+        None => Ok(Box::new(JsonStorage::new("."))),
+    }
+}
+
+pub fn init() {
+    lazy_static::initialize(&STORAGE);
+}
+
+pub fn storage() -> &'static dyn Storage {
+    STORAGE.as_ref()
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+
+    // A fresh directory for a test, removed again when it is dropped.
+    pub(super) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(super) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("littleguy-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub(super) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(super) fn texts(messages: &[ChatMessage]) -> Vec<String> {
+        messages
+            .iter()
+            .filter_map(|message| message.text())
+            .map(|text| text.into_owned())
+            .collect()
+    }
+
+    fn usage(day: &str, user_id: u64, cost: f64) -> UsageRecord {
+        UsageRecord {
+            day: day.to_string(),
+            user_id,
+            channel_id: 10,
+            model: "gpt-4".to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 50,
+            cost,
+        }
+    }
+
+    fn memory(scope: u64, text: &str) -> Memory {
+        Memory {
+            scope: Some(scope),
+            text: text.to_string(),
+            vector: vec![1.0, 0.0],
+        }
+    }
+
+    // What every backend has to do, so both are held to the same behavior.
+    pub(super) fn round_trip(storage: &dyn Storage) {
+        for text in ["one", "two", "three"] {
+            storage
+                .push_message(1, &ChatMessage::user(None, text.to_string()))
+                .unwrap();
+        }
+        storage
+            .push_message(2, &ChatMessage::assistant("elsewhere"))
+            .unwrap();
+        assert_eq!(
+            texts(&storage.messages(1).unwrap()),
+            ["one", "two", "three"]
+        );
+        storage.remove_oldest_messages(1, 2).unwrap();
+        assert_eq!(texts(&storage.messages(1).unwrap()), ["three"]);
+        storage.remove_oldest_messages(1, 5).unwrap();
+        assert!(storage.messages(1).unwrap().is_empty());
+        storage.clear_messages(2).unwrap();
+        assert!(storage.messages(2).unwrap().is_empty());
+
+        assert_eq!(storage.opinion(5).unwrap(), None);
+        assert_eq!(
+            storage
+                .update_opinion(5, &|old| old.unwrap_or(50) + 10)
+                .unwrap(),
+            60
+        );
+        assert_eq!(
+            storage
+                .update_opinion(5, &|old| old.unwrap_or(50) + 10)
+                .unwrap(),
+            70
+        );
+        assert_eq!(storage.opinion(5).unwrap(), Some(70));
+
+        storage.set_summary(1, Some("we talked")).unwrap();
+        assert_eq!(storage.summary(1).unwrap().as_deref(), Some("we talked"));
+        storage.set_summary(1, None).unwrap();
+        assert_eq!(storage.summary(1).unwrap(), None);
+
+        storage.record_usage(&usage("2024-02-29", 5, 1.0)).unwrap();
+        storage.record_usage(&usage("2024-03-01", 5, 1.0)).unwrap();
+        storage.record_usage(&usage("2024-03-01", 5, 0.5)).unwrap();
+        storage.record_usage(&usage("2024-03-01", 6, 2.0)).unwrap();
+        let mut records = storage.usage_since("2024-03-01").unwrap();
+        records.sort_by_key(|record| record.user_id);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].cost, 1.5);
+        assert_eq!(records[0].prompt_tokens, 200);
+        assert_eq!(records[1].cost, 2.0);
+
+        let engaged = Conversation::Engaged {
+            last_active: 100,
+            unaddressed: 1,
+        };
+        let stored = storage
+            .update_conversation(1, &|old| {
+                assert_eq!(old, None);
+                engaged
+            })
+            .unwrap();
+        assert_eq!(stored, engaged);
+        storage
+            .update_conversation(1, &|old| {
+                assert_eq!(old, Some(engaged));
+                Conversation::Idle
+            })
+            .unwrap();
+
+        storage
+            .add_memories(&[memory(1, "a"), memory(1, "b")], 3)
+            .unwrap();
+        storage
+            .add_memories(&[memory(2, "c"), memory(2, "d")], 3)
+            .unwrap();
+        let memories = storage.memories().unwrap();
+        let memories: Vec<&str> = memories.iter().map(|memory| memory.text.as_str()).collect();
+        assert_eq!(memories, ["b", "c", "d"]);
+    }
+}
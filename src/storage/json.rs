@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Storage, UsageRecord};
+use crate::{conversation::Conversation, memory::Memory, provider::ChatMessage};
+
+type Histories = HashMap<String, Vec<ChatMessage>>;
+
+// Stores everything in json files which are re-read and re-written on every
+// access. All access goes through a lock so that concurrent event handlers
+// don't lose each other's writes.
+pub struct JsonStorage {
+    messages_path: PathBuf,
+    opinions_path: PathBuf,
+    summaries_path: PathBuf,
+    usage_path: PathBuf,
+    conversations_path: PathBuf,
+    memories_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl JsonStorage {
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        let directory = directory.as_ref();
+        Self {
+            messages_path: directory.join("messages.json"),
+            opinions_path: directory.join("opinions.json"),
+            summaries_path: directory.join("summaries.json"),
+            usage_path: directory.join("usage.json"),
+            conversations_path: directory.join("conversations.json"),
+            memories_path: directory.join("memory.json"),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn messages_path(&self) -> &Path {
+        &self.messages_path
+    }
+
+    pub fn opinions_path(&self) -> &Path {
+        &self.opinions_path
+    }
+
+    pub fn memories_path(&self) -> &Path {
+        &self.memories_path
+    }
+
+    fn lock(&self) -> MutexGuard<()> {
+        // The guarded data is on disk, so a panic while holding the lock
+        // doesn't leave anything half updated in memory.
+        self.lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Histories are keyed by channel id. Threads have their own channel id, so
+    // each thread gets its own transcript as well.
+    pub fn histories(&self) -> Result<Histories> {
+        let contents = match std::fs::read_to_string(&self.messages_path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Could not read {:?}", self.messages_path))
+            }
+        };
+
+        // Before histories were split by channel, the file held a single list
+        // of messages. There is no way to tell which channel those belong to,
+        // so they are moved out of the way before anything writes over them.
+        if serde_json::from_str::<Vec<ChatMessage>>(&contents).is_ok() {
+            let legacy_path = self.messages_path.with_extension("legacy.json");
+            std::fs::rename(&self.messages_path, &legacy_path)
+                .with_context(|| format!("Could not move {:?}", self.messages_path))?;
+            println!(
+                "Moved single channel history in {:?} to {:?}",
+                self.messages_path, legacy_path
+            );
+            return Ok(Default::default());
+        }
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("{:?} exists but could not be parsed", self.messages_path))
+    }
+
+    pub fn opinions(&self) -> Result<HashMap<String, u8>> {
+        read_json(&self.opinions_path)
+    }
+
+    pub fn summaries(&self) -> Result<HashMap<String, String>> {
+        read_json(&self.summaries_path)
+    }
+
+    pub fn usage(&self) -> Result<Vec<UsageRecord>> {
+        read_json(&self.usage_path)
+    }
+
+    pub fn conversations(&self) -> Result<HashMap<String, Conversation>> {
+        read_json(&self.conversations_path)
+    }
+
+    fn read_memories(&self) -> Result<Vec<Memory>> {
+        read_json(&self.memories_path)
+    }
+
+    fn update_histories(&self, update: impl FnOnce(&mut Histories)) -> Result<()> {
+        let _guard = self.lock();
+        let mut histories = self.histories()?;
+        update(&mut histories);
+        write_json(&self.messages_path, &histories)
+    }
+}
+
+impl Storage for JsonStorage {
+    fn messages(&self, channel_id: u64) -> Result<Vec<ChatMessage>> {
+        let _guard = self.lock();
+        Ok(self
+            .histories()?
+            .remove(&channel_id.to_string())
+            .unwrap_or_default())
+    }
+
+    fn push_message(&self, channel_id: u64, message: &ChatMessage) -> Result<()> {
+        self.update_histories(|histories| {
+            histories
+                .entry(channel_id.to_string())
+                .or_default()
+                .push(message.clone());
+        })
+    }
+
+    fn remove_oldest_messages(&self, channel_id: u64, count: usize) -> Result<()> {
+        self.update_histories(|histories| {
+            if let Some(messages) = histories.get_mut(&channel_id.to_string()) {
+                messages.drain(..count.min(messages.len()));
+            }
+        })
+    }
+
+    fn clear_messages(&self, channel_id: u64) -> Result<()> {
+        self.update_histories(|histories| {
+            histories.remove(&channel_id.to_string());
+        })
+    }
+
+    fn opinion(&self, user_id: u64) -> Result<Option<u8>> {
+        let _guard = self.lock();
+        Ok(self.opinions()?.get(&user_id.to_string()).cloned())
+    }
+
+    fn update_opinion(&self, user_id: u64, update: &dyn Fn(Option<u8>) -> u8) -> Result<u8> {
+        let _guard = self.lock();
+        let mut opinions = self.opinions()?;
+        let user_id = user_id.to_string();
+        let new_opinion = update(opinions.get(&user_id).cloned());
+        opinions.insert(user_id, new_opinion);
+        write_json(&self.opinions_path, &opinions)?;
+        Ok(new_opinion)
+    }
+
+    fn summary(&self, channel_id: u64) -> Result<Option<String>> {
+        let _guard = self.lock();
+        Ok(self.summaries()?.remove(&channel_id.to_string()))
+    }
+
+    fn set_summary(&self, channel_id: u64, summary: Option<&str>) -> Result<()> {
+        let _guard = self.lock();
+        let mut summaries = self.summaries()?;
+        match summary {
+            Some(summary) => summaries.insert(channel_id.to_string(), summary.to_string()),
+            None => summaries.remove(&channel_id.to_string()),
+        };
+        write_json(&self.summaries_path, &summaries)
+    }
+
+    fn record_usage(&self, usage: &UsageRecord) -> Result<()> {
+        let _guard = self.lock();
+        let mut records = self.usage()?;
+        match records.iter_mut().find(|record| record.same_totals(usage)) {
+            Some(record) => {
+                record.prompt_tokens += usage.prompt_tokens;
+                record.completion_tokens += usage.completion_tokens;
+                record.cost += usage.cost;
+            }
+            None => records.push(usage.clone()),
+        }
+        write_json(&self.usage_path, &records)
+    }
+
+    fn usage_since(&self, day: &str) -> Result<Vec<UsageRecord>> {
+        let _guard = self.lock();
+        Ok(self
+            .usage()?
+            .into_iter()
+            .filter(|record| record.day.as_str() >= day)
+            .collect())
+    }
+
+    fn update_conversation(
+        &self,
+        channel_id: u64,
+        update: &dyn Fn(Option<Conversation>) -> Conversation,
+    ) -> Result<Conversation> {
+        let _guard = self.lock();
+        let mut conversations = self.conversations()?;
+        let channel_id = channel_id.to_string();
+        let conversation = update(conversations.get(&channel_id).cloned());
+        conversations.insert(channel_id, conversation);
+        write_json(&self.conversations_path, &conversations)?;
+        Ok(conversation)
+    }
+
+    fn memories(&self) -> Result<Vec<Memory>> {
+        let _guard = self.lock();
+        self.read_memories()
+    }
+
+    fn add_memories(&self, memories: &[Memory], limit: usize) -> Result<()> {
+        let _guard = self.lock();
+        let mut stored = self.read_memories()?;
+        stored.extend_from_slice(memories);
+        stored.drain(..stored.len().saturating_sub(limit));
+        write_json(&self.memories_path, &stored)
+    }
+}
+
+// A missing file is treated as empty, but a file which exists and fails to
+// parse is an error so that it doesn't get silently overwritten.
+pub fn read_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("{:?} exists but could not be parsed", path)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(error) => Err(error).with_context(|| format!("Could not read {:?}", path)),
+    }
+}
+
+// Writes to a temporary file next to the destination and renames it over the
+// original so that a crash mid write never leaves a truncated file behind.
+pub fn write_json(path: &Path, value: &impl Serialize) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file =
+        File::create(&temp_path).with_context(|| format!("Could not create {:?}", temp_path))?;
+    file.write_all(serde_json::to_string_pretty(value)?.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path).with_context(|| format!("Could not replace {:?}", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{round_trip, texts, TempDir};
+
+    #[test]
+    fn stores_everything() {
+        let dir = TempDir::new("json-round-trip");
+        round_trip(&JsonStorage::new(dir.path()));
+    }
+
+    #[test]
+    fn writes_replace_the_file_whole() {
+        let dir = TempDir::new("json-write");
+        let path = dir.path().join("opinions.json");
+        write_json(&path, &HashMap::from([("1", 10)])).unwrap();
+        write_json(&path, &HashMap::from([("2", 20)])).unwrap();
+
+        let opinions: HashMap<String, u8> = read_json(&path).unwrap();
+        assert_eq!(opinions, HashMap::from([("2".to_string(), 20)]));
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["opinions.json"]);
+    }
+
+    #[test]
+    fn missing_files_are_empty() {
+        let dir = TempDir::new("json-missing");
+        let storage = JsonStorage::new(dir.path());
+        assert!(storage.messages(1).unwrap().is_empty());
+        assert_eq!(storage.opinion(1).unwrap(), None);
+        assert!(storage.memories().unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupt_files_fail_loudly_and_are_kept() {
+        let dir = TempDir::new("json-corrupt");
+        let storage = JsonStorage::new(dir.path());
+        std::fs::write(storage.opinions_path(), "{\"1\": 10,").unwrap();
+        std::fs::write(storage.messages_path(), "{\"1\": [").unwrap();
+
+        assert!(storage.opinion(1).is_err());
+        assert!(storage.update_opinion(1, &|_| 20).is_err());
+        assert!(storage.messages(1).is_err());
+        assert!(storage
+            .push_message(1, &ChatMessage::assistant("hi"))
+            .is_err());
+        assert_eq!(
+            std::fs::read_to_string(storage.opinions_path()).unwrap(),
+            "{\"1\": 10,"
+        );
+        assert_eq!(
+            std::fs::read_to_string(storage.messages_path()).unwrap(),
+            "{\"1\": ["
+        );
+    }
+
+    #[test]
+    fn single_channel_history_is_moved_aside() {
+        let dir = TempDir::new("json-legacy");
+        let storage = JsonStorage::new(dir.path());
+        let legacy = vec![ChatMessage::user(None, "old".to_string())];
+        write_json(storage.messages_path(), &legacy).unwrap();
+
+        assert!(storage.messages(1).unwrap().is_empty());
+        let legacy_path = dir.path().join("messages.legacy.json");
+        let moved: Vec<ChatMessage> = read_json(&legacy_path).unwrap();
+        assert_eq!(texts(&moved), ["old"]);
+        assert!(!storage.messages_path().exists());
+
+        storage
+            .push_message(1, &ChatMessage::user(None, "new".to_string()))
+            .unwrap();
+        assert_eq!(texts(&storage.messages(1).unwrap()), ["new"]);
+        let moved: Vec<ChatMessage> = read_json(&legacy_path).unwrap();
+        assert_eq!(texts(&moved), ["old"]);
+    }
+}
@@ -0,0 +1,465 @@
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{now, JsonStorage, Storage, UsageRecord};
+use crate::{conversation::Conversation, memory::Memory, provider::ChatMessage};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        channel_id INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_channel ON messages (channel_id, id);
+
+    CREATE TABLE IF NOT EXISTS opinions (
+        user_id INTEGER PRIMARY KEY,
+        opinion INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS summaries (
+        channel_id INTEGER PRIMARY KEY,
+        summary TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS usage (
+        day TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        channel_id INTEGER NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        cost REAL NOT NULL,
+        PRIMARY KEY (day, user_id, channel_id, model)
+    );
+
+    CREATE TABLE IF NOT EXISTS conversations (
+        channel_id INTEGER PRIMARY KEY,
+        state TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS memories (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        scope INTEGER,
+        text TEXT NOT NULL,
+        vector TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS imports (
+        file TEXT PRIMARY KEY,
+        imported_at INTEGER NOT NULL
+    );
+";
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    // Opens the database, creating the tables if needed. The json files from
+    // the old storage in json_directory are imported the first time the
+    // database is opened.
+    pub fn open(path: impl AsRef<Path>, json_directory: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path.as_ref())
+            .with_context(|| format!("Could not open database {:?}", path.as_ref()))?;
+        // Write ahead logging keeps the database intact if the bot dies
+        // halfway through a write.
+        connection.execute_batch("PRAGMA journal_mode = WAL;")?;
+        connection.execute_batch(SCHEMA)?;
+
+        let storage = Self {
+            connection: Mutex::new(connection),
+        };
+        storage.import_json(&JsonStorage::new(json_directory))?;
+
+        Ok(storage)
+    }
+
+    fn import_json(&self, json: &JsonStorage) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let messages_json = json.messages_path();
+        let opinions_json = json.opinions_path();
+        let memories_json = json.memories_path();
+
+        let already_imported = |file: &Path| -> Result<bool> {
+            Ok(transaction
+                .query_row(
+                    "SELECT 1 FROM imports WHERE file = ?1",
+                    params![file.to_string_lossy()],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some())
+        };
+
+        if messages_json.exists() && !already_imported(messages_json)? {
+            let histories = json.histories()?;
+            // Reading a single channel history moves it aside, and then
+            // there is nothing to import or to mark as imported.
+            if messages_json.exists() {
+                for (channel_id, messages) in histories {
+                    let channel_id: i64 = channel_id.parse()?;
+                    for message in messages {
+                        transaction.execute(
+                            "INSERT INTO messages (channel_id, message, created_at) VALUES (?1, ?2, ?3)",
+                            params![channel_id, serde_json::to_string(&message)?, now()],
+                        )?;
+                    }
+                }
+                transaction.execute(
+                    "INSERT INTO imports (file, imported_at) VALUES (?1, ?2)",
+                    params![messages_json.to_string_lossy(), now()],
+                )?;
+                println!("Imported {:?} into the database", messages_json);
+            }
+        }
+
+        if opinions_json.exists() && !already_imported(opinions_json)? {
+            for (user_id, opinion) in json.opinions()? {
+                let user_id: i64 = user_id.parse()?;
+                transaction.execute(
+                    "INSERT OR REPLACE INTO opinions (user_id, opinion, updated_at) VALUES (?1, ?2, ?3)",
+                    params![user_id, opinion, now()],
+                )?;
+            }
+            transaction.execute(
+                "INSERT INTO imports (file, imported_at) VALUES (?1, ?2)",
+                params![opinions_json.to_string_lossy(), now()],
+            )?;
+            println!("Imported {:?} into the database", opinions_json);
+        }
+
+        if memories_json.exists() && !already_imported(memories_json)? {
+            for memory in json.memories()? {
+                insert_memory(&transaction, &memory)?;
+            }
+            transaction.execute(
+                "INSERT INTO imports (file, imported_at) VALUES (?1, ?2)",
+                params![memories_json.to_string_lossy(), now()],
+            )?;
+            println!("Imported {:?} into the database", memories_json);
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+fn insert_memory(connection: &Connection, memory: &Memory) -> Result<()> {
+    connection.execute(
+        "INSERT INTO memories (scope, text, vector, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            memory.scope.map(|scope| scope as i64),
+            memory.text,
+            serde_json::to_string(&memory.vector)?,
+            now()
+        ],
+    )?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn messages(&self, channel_id: u64) -> Result<Vec<ChatMessage>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT message FROM messages WHERE channel_id = ?1 ORDER BY id")?;
+        let messages = statement
+            .query_map(params![channel_id as i64], |row| row.get::<_, String>(0))?
+            .map(|message| Ok(serde_json::from_str(&message?)?))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(messages)
+    }
+
+    fn push_message(&self, channel_id: u64, message: &ChatMessage) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO messages (channel_id, message, created_at) VALUES (?1, ?2, ?3)",
+            params![channel_id as i64, serde_json::to_string(message)?, now()],
+        )?;
+        Ok(())
+    }
+
+    fn remove_oldest_messages(&self, channel_id: u64, count: usize) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM messages WHERE id IN (
+                SELECT id FROM messages WHERE channel_id = ?1 ORDER BY id LIMIT ?2
+            )",
+            params![channel_id as i64, count as i64],
+        )?;
+        Ok(())
+    }
+
+    fn clear_messages(&self, channel_id: u64) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM messages WHERE channel_id = ?1",
+            params![channel_id as i64],
+        )?;
+        Ok(())
+    }
+
+    fn opinion(&self, user_id: u64) -> Result<Option<u8>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT opinion FROM opinions WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn update_opinion(&self, user_id: u64, update: &dyn Fn(Option<u8>) -> u8) -> Result<u8> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let opinion = transaction
+            .query_row(
+                "SELECT opinion FROM opinions WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let new_opinion = update(opinion);
+        transaction.execute(
+            "INSERT OR REPLACE INTO opinions (user_id, opinion, updated_at) VALUES (?1, ?2, ?3)",
+            params![user_id as i64, new_opinion, now()],
+        )?;
+        transaction.commit()?;
+        Ok(new_opinion)
+    }
+
+    fn summary(&self, channel_id: u64) -> Result<Option<String>> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT summary FROM summaries WHERE channel_id = ?1",
+                params![channel_id as i64],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_summary(&self, channel_id: u64, summary: Option<&str>) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        match summary {
+            Some(summary) => connection.execute(
+                "INSERT OR REPLACE INTO summaries (channel_id, summary, updated_at) VALUES (?1, ?2, ?3)",
+                params![channel_id as i64, summary, now()],
+            )?,
+            None => connection.execute(
+                "DELETE FROM summaries WHERE channel_id = ?1",
+                params![channel_id as i64],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn record_usage(&self, usage: &UsageRecord) -> Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO usage (day, user_id, channel_id, model, prompt_tokens, completion_tokens, cost)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (day, user_id, channel_id, model) DO UPDATE SET
+                 prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                 completion_tokens = completion_tokens + excluded.completion_tokens,
+                 cost = cost + excluded.cost",
+            params![
+                usage.day,
+                usage.user_id as i64,
+                usage.channel_id as i64,
+                usage.model,
+                usage.prompt_tokens as i64,
+                usage.completion_tokens as i64,
+                usage.cost
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn usage_since(&self, day: &str) -> Result<Vec<UsageRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT day, user_id, channel_id, model, prompt_tokens, completion_tokens, cost
+             FROM usage WHERE day >= ?1",
+        )?;
+        let records = statement
+            .query_map(params![day], |row| {
+                Ok(UsageRecord {
+                    day: row.get(0)?,
+                    user_id: row.get::<_, i64>(1)? as u64,
+                    channel_id: row.get::<_, i64>(2)? as u64,
+                    model: row.get(3)?,
+                    prompt_tokens: row.get::<_, i64>(4)? as u64,
+                    completion_tokens: row.get::<_, i64>(5)? as u64,
+                    cost: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+
+    fn update_conversation(
+        &self,
+        channel_id: u64,
+        update: &dyn Fn(Option<Conversation>) -> Conversation,
+    ) -> Result<Conversation> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let state: Option<String> = transaction
+            .query_row(
+                "SELECT state FROM conversations WHERE channel_id = ?1",
+                params![channel_id as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let conversation = update(
+            state
+                .map(|state| serde_json::from_str(&state))
+                .transpose()?,
+        );
+        transaction.execute(
+            "INSERT OR REPLACE INTO conversations (channel_id, state, updated_at) VALUES (?1, ?2, ?3)",
+            params![channel_id as i64, serde_json::to_string(&conversation)?, now()],
+        )?;
+        transaction.commit()?;
+        Ok(conversation)
+    }
+
+    fn memories(&self) -> Result<Vec<Memory>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT scope, text, vector FROM memories ORDER BY id")?;
+        let memories = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, Option<i64>>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .map(|row| {
+                let (scope, text, vector) = row?;
+                Ok(Memory {
+                    scope: scope.map(|scope| scope as u64),
+                    text,
+                    vector: serde_json::from_str(&vector)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(memories)
+    }
+
+    fn add_memories(&self, memories: &[Memory], limit: usize) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for memory in memories {
+            insert_memory(&transaction, memory)?;
+        }
+        transaction.execute(
+            "DELETE FROM memories WHERE id NOT IN (
+                SELECT id FROM memories ORDER BY id DESC LIMIT ?1
+            )",
+            params![limit as i64],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::storage::{
+        json::write_json,
+        tests::{round_trip, texts, TempDir},
+    };
+
+    #[test]
+    fn stores_everything() {
+        let dir = TempDir::new("sqlite-round-trip");
+        round_trip(&SqliteStorage::open(dir.path().join("astro.db"), dir.path()).unwrap());
+    }
+
+    #[test]
+    fn keeps_everything_after_reopening() {
+        let dir = TempDir::new("sqlite-reopen");
+        let database = dir.path().join("astro.db");
+        let storage = SqliteStorage::open(&database, dir.path()).unwrap();
+        storage
+            .push_message(1, &ChatMessage::assistant("hello"))
+            .unwrap();
+        storage.update_opinion(5, &|_| 70).unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::open(&database, dir.path()).unwrap();
+        assert_eq!(texts(&storage.messages(1).unwrap()), ["hello"]);
+        assert_eq!(storage.opinion(5).unwrap(), Some(70));
+    }
+
+    #[test]
+    fn json_files_are_imported_once() {
+        let dir = TempDir::new("sqlite-import");
+        let json = JsonStorage::new(dir.path());
+        let histories = HashMap::from([(
+            "1".to_string(),
+            vec![ChatMessage::user(None, "imported".to_string())],
+        )]);
+        write_json(json.messages_path(), &histories).unwrap();
+        write_json(json.opinions_path(), &HashMap::from([("5", 80)])).unwrap();
+        let memories = vec![Memory {
+            scope: Some(1),
+            text: "likes tea".to_string(),
+            vector: vec![1.0],
+        }];
+        write_json(json.memories_path(), &memories).unwrap();
+
+        let database = dir.path().join("astro.db");
+        let storage = SqliteStorage::open(&database, dir.path()).unwrap();
+        assert_eq!(texts(&storage.messages(1).unwrap()), ["imported"]);
+        assert_eq!(storage.opinion(5).unwrap(), Some(80));
+        assert_eq!(storage.memories().unwrap().len(), 1);
+        storage.update_opinion(5, &|_| 60).unwrap();
+        drop(storage);
+
+        // The json files are still there, but reopening doesn't import them
+        // again on top of what is in the database.
+        let storage = SqliteStorage::open(&database, dir.path()).unwrap();
+        assert_eq!(texts(&storage.messages(1).unwrap()), ["imported"]);
+        assert_eq!(storage.opinion(5).unwrap(), Some(60));
+        assert_eq!(storage.memories().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn single_channel_history_is_not_imported() {
+        let dir = TempDir::new("sqlite-legacy");
+        let json = JsonStorage::new(dir.path());
+        write_json(
+            json.messages_path(),
+            &vec![ChatMessage::user(None, "old".to_string())],
+        )
+        .unwrap();
+
+        let storage = SqliteStorage::open(dir.path().join("astro.db"), dir.path()).unwrap();
+        assert!(storage.messages(1).unwrap().is_empty());
+        assert!(dir.path().join("messages.legacy.json").exists());
+    }
+
+    #[test]
+    fn corrupt_json_fails_the_import() {
+        let dir = TempDir::new("sqlite-corrupt");
+        let json = JsonStorage::new(dir.path());
+        std::fs::write(json.opinions_path(), "{").unwrap();
+        assert!(SqliteStorage::open(dir.path().join("astro.db"), dir.path()).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use indoc::indoc;
+use itertools::Itertools;
+
+use crate::{
+    config::config,
+    provider::{provider, ChatMessage, ChatRequest, Role},
+    storage::storage,
+    usage,
+};
+
+const SUMMARY_PROMPT: &str = indoc! {"
+    You maintain a running summary of a Discord conversation that a chat bot
+    named Astro is part of. You are given the current summary and some older
+    messages which are about to be forgotten. Reply with an updated summary
+    which folds in anything from the messages worth remembering: topics,
+    decisions, questions that are still open and how people were feeling.
+    Drop details that no longer matter. Keep it under 200 words and reply
+    with the summary only.
+"};
+
+pub fn summary(channel_id: u64) -> Result<Option<String>> {
+    storage().summary(channel_id)
+}
+
+pub fn clear(channel_id: u64) -> Result<()> {
+    storage().set_summary(channel_id, None)
+}
+
+pub fn transcript_line(message: &ChatMessage) -> Option<String> {
+    let content = message.text()?;
+    match message.role {
+        Role::User => Some(format!(
+            "{}: {}",
+            message.name.as_deref().unwrap_or("Someone"),
+            content
+        )),
+        Role::Assistant => Some(format!("Astro: {}", content)),
+        _ => None,
+    }
+}
+
+// Folds messages which were evicted from the history into the channel's
+// running summary. The summary is read before the model call and written
+// after it, so callers have to make sure a channel only has one fold at a
+// time.
+pub async fn fold(channel_id: u64, evicted: &[ChatMessage]) -> Result<()> {
+    let transcript = evicted.iter().filter_map(transcript_line).join("\n");
+    if transcript.is_empty() {
+        return Ok(());
+    }
+
+    let previous_summary = summary(channel_id)?.unwrap_or_else(|| "(empty)".to_string());
+
+    let request = ChatRequest::new(
+        config().model.clone(),
+        vec![
+            ChatMessage::system(SUMMARY_PROMPT),
+            ChatMessage::user(
+                None,
+                format!("Current summary:\n{previous_summary}\n\nMessages:\n{transcript}"),
+            ),
+        ],
+    );
+    let response = provider().complete(&request).await?;
+    if let Err(why) = usage::record(None, channel_id, &request, &response) {
+        println!("Error recording usage: {:?}", why);
+    }
+
+    if let Some(new_summary) = response.message.text() {
+        storage().set_summary(channel_id, Some(new_summary.trim()))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use crate::{
+    config::config,
+    provider::{ChatMessage, Role, ToolDefinition},
+};
+
+// Every message costs a few tokens on top of its content for the role and
+// separators.
+const MESSAGE_OVERHEAD: usize = 4;
+
+// Rough estimate without a tokenizer. English averages about four characters
+// per token, and emoji or other non ascii text tends to cost more.
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(|c| c.is_ascii()).count();
+    let other = text.chars().count() - ascii;
+    ascii.div_ceil(4) + other
+}
+
+pub fn message_tokens(message: &ChatMessage) -> usize {
+    let mut tokens = MESSAGE_OVERHEAD;
+    if let Some(content) = message.content.as_ref() {
+        tokens += estimate_tokens(&content.text());
+        let images = content.image_count();
+        if images > 0 {
+            tokens += images * image_tokens();
+        }
+    }
+    if let Some(name) = message.name.as_ref() {
+        tokens += estimate_tokens(name);
+    }
+    for tool_call in message.tool_calls() {
+        tokens += estimate_tokens(&tool_call.id)
+            + estimate_tokens(&tool_call.function.name)
+            + estimate_tokens(&tool_call.function.arguments);
+    }
+    tokens
+}
+
+// Images cost a flat amount at low detail, and up to several times that at
+// high detail depending on their size.
+pub fn image_tokens() -> usize {
+    match config().vision.detail.as_str() {
+        "low" => 85,
+        _ => 765,
+    }
+}
+
+pub fn tool_tokens(tools: &[ToolDefinition]) -> usize {
+    serde_json::to_string(tools)
+        .map(|tools| estimate_tokens(&tools))
+        .unwrap_or_default()
+}
+
+// Tokens available for the transcript once the identity prompt, the tool
+// schemas and the reply are accounted for.
+pub fn history_budget(model: &str, identity: &str, tools: &[ToolDefinition]) -> usize {
+    config()
+        .context_size(model)
+        .saturating_sub(config().history.response_tokens)
+        .saturating_sub(estimate_tokens(identity) + MESSAGE_OVERHEAD)
+        .saturating_sub(tool_tokens(tools))
+}
+
+// Returns how many messages have to be dropped from the front of the
+// transcript for it to fit in the budget. The most recent message is always
+// kept, and tool results are never left at the front without the call that
+// produced them.
+pub fn overflowing_messages(messages: &[ChatMessage], budget: usize) -> usize {
+    let mut total: usize = messages.iter().map(message_tokens).sum();
+    let mut count = 0;
+
+    while count + 1 < messages.len() && total > budget {
+        total -= message_tokens(&messages[count]);
+        count += 1;
+    }
+
+    while count < messages.len() && matches!(messages[count].role, Role::Tool | Role::Function) {
+        count += 1;
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(role: Role, tokens: usize) -> ChatMessage {
+        // Four ascii characters per token, minus the overhead.
+        let content = "abcd".repeat(tokens - MESSAGE_OVERHEAD);
+        match role {
+            Role::Tool => ChatMessage::tool("call", content),
+            _ => ChatMessage::user(None, content),
+        }
+    }
+
+    #[test]
+    fn estimates_ascii_and_other_text() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("😀😀"), 2);
+        assert_eq!(estimate_tokens("héllo"), 2);
+    }
+
+    #[test]
+    fn nothing_overflows_within_the_budget() {
+        let messages = vec![text(Role::User, 10), text(Role::User, 10)];
+        assert_eq!(message_tokens(&messages[0]), 10);
+        assert_eq!(overflowing_messages(&messages, 20), 0);
+        assert_eq!(overflowing_messages(&[], 0), 0);
+    }
+
+    #[test]
+    fn drops_the_oldest_messages_first() {
+        let messages = vec![
+            text(Role::User, 10),
+            text(Role::User, 10),
+            text(Role::User, 10),
+        ];
+        assert_eq!(overflowing_messages(&messages, 25), 1);
+        assert_eq!(overflowing_messages(&messages, 15), 2);
+    }
+
+    #[test]
+    fn keeps_the_most_recent_message() {
+        let messages = vec![text(Role::User, 10), text(Role::User, 50)];
+        assert_eq!(overflowing_messages(&messages, 20), 1);
+    }
+
+    #[test]
+    fn never_leaves_results_without_their_call() {
+        let messages = vec![
+            text(Role::User, 10),
+            text(Role::User, 10),
+            text(Role::Tool, 10),
+            text(Role::Tool, 10),
+            text(Role::User, 10),
+        ];
+        for budget in 0..60 {
+            let count = overflowing_messages(&messages, budget);
+            assert!(
+                messages
+                    .get(count)
+                    .is_none_or(|message| message.role != Role::Tool),
+                "{count} messages dropped for a budget of {budget}"
+            );
+        }
+        assert_eq!(overflowing_messages(&messages, 35), 4);
+    }
+}
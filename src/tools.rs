@@ -0,0 +1,170 @@
+mod arguments;
+mod chat;
+mod people;
+mod remember;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use serenity::{async_trait, model::prelude::Message, prelude::Context};
+
+use crate::provider::{FunctionCall, FunctionDefinition, ToolDefinition};
+
+// What the handlers of a tool call get to work with.
+pub struct ToolContext<'a> {
+    pub ctx: &'a Context,
+    // The message Astro is responding to.
+    pub message: &'a Message,
+}
+
+// What running a tool call produced. Tools which only act on the chat, like
+// reacting, don't need the model to look at their result.
+pub struct ToolOutcome {
+    pub result: String,
+    pub follow_up: bool,
+}
+
+impl ToolOutcome {
+    pub fn follow_up(result: impl Into<String>) -> Self {
+        Self {
+            result: result.into(),
+            follow_up: true,
+        }
+    }
+
+    pub fn done(result: impl Into<String>) -> Self {
+        Self {
+            result: result.into(),
+            follow_up: false,
+        }
+    }
+}
+
+// Arguments for tools which don't take any.
+#[derive(Deserialize, JsonSchema)]
+pub struct NoArguments {}
+
+// A capability the model can call. The schema sent to the model is derived
+// from the arguments type, so it can't drift from what the handler parses.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    type Arguments: DeserializeOwned + JsonSchema + Send;
+
+    const NAME: &'static str;
+    const DESCRIPTION: &'static str;
+
+    async fn run(
+        &self,
+        context: &ToolContext<'_>,
+        arguments: Self::Arguments,
+    ) -> Result<ToolOutcome>;
+}
+
+// Object safe version of Tool so that tools with different arguments can sit
+// in the same registry.
+#[async_trait]
+trait RegisteredTool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn definition(&self) -> ToolDefinition;
+    async fn call(&self, context: &ToolContext<'_>, arguments: &str) -> Result<ToolOutcome>;
+}
+
+#[async_trait]
+impl<T: Tool> RegisteredTool for T {
+    fn name(&self) -> &'static str {
+        T::NAME
+    }
+
+    fn definition(&self) -> ToolDefinition {
+        FunctionDefinition {
+            name: T::NAME.to_string(),
+            description: Some(T::DESCRIPTION.to_string()),
+            parameters: Some(schema::<T::Arguments>()),
+        }
+        .into()
+    }
+
+    async fn call(&self, context: &ToolContext<'_>, arguments: &str) -> Result<ToolOutcome> {
+        let schema = schema::<T::Arguments>();
+        let parsed = arguments::parse(arguments)
+            .and_then(|value| arguments::validate(&value, &schema).map(|()| value))
+            .and_then(|value| serde_json::from_value(value).map_err(|why| why.to_string()));
+
+        match parsed {
+            Ok(arguments) => self.run(context, arguments).await,
+            Err(why) => {
+                println!("Invalid arguments for {}: {}", T::NAME, why);
+                Ok(error_outcome(
+                    "invalid_arguments",
+                    &why,
+                    json!({ "tool": T::NAME, "parameters": schema }),
+                ))
+            }
+        }
+    }
+}
+
+// Sent back instead of running the call, with enough detail for the model to
+// fix it and try again.
+fn error_outcome(error: &str, message: &str, details: Value) -> ToolOutcome {
+    let mut result = json!({ "error": error, "message": message });
+    if let (Some(result), Value::Object(details)) = (result.as_object_mut(), details) {
+        result.extend(details);
+    }
+    ToolOutcome::follow_up(result.to_string())
+}
+
+// Json schema for a tool's arguments, trimmed down to what the api expects.
+fn schema<T: JsonSchema>() -> Value {
+    let generator = SchemaSettings::draft07()
+        .with(|settings| {
+            settings.inline_subschemas = true;
+            settings.meta_schema = None;
+        })
+        .into_generator();
+    let mut schema = serde_json::to_value(generator.into_root_schema_for::<T>())
+        .expect("Schemas always serialize");
+    if let Some(schema) = schema.as_object_mut() {
+        schema.remove("title");
+    }
+    schema
+}
+
+lazy_static! {
+    // Every tool the model can call.
+    static ref TOOLS: Vec<Box<dyn RegisteredTool>> = vec![
+        Box::new(chat::React),
+        Box::new(chat::Pin),
+        Box::new(chat::Unpin),
+        Box::new(people::GetUsers),
+        Box::new(people::Angry),
+        Box::new(people::Happy),
+        Box::new(people::GetOpinion),
+        Box::new(remember::Remember),
+        Box::new(chat::StopListening),
+    ];
+}
+
+// Whether the call ends Astro's part in the conversation, which leaves
+// nothing more to say.
+pub fn ends_conversation(call: &FunctionCall) -> bool {
+    call.name == chat::StopListening::NAME
+}
+
+pub fn definitions() -> Vec<ToolDefinition> {
+    TOOLS.iter().map(|tool| tool.definition()).collect()
+}
+
+pub async fn run(context: &ToolContext<'_>, call: &FunctionCall) -> Result<ToolOutcome> {
+    let Some(tool) = TOOLS.iter().find(|tool| tool.name() == call.name) else {
+        println!("Unknown tool {}", call.name);
+        return Ok(error_outcome(
+            "unknown_tool",
+            &format!("There is no tool called {}", call.name),
+            json!({ "tools": TOOLS.iter().map(|tool| tool.name()).collect::<Vec<_>>() }),
+        ));
+    };
+    tool.call(context, &call.arguments).await
+}
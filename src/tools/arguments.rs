@@ -0,0 +1,270 @@
+use serde_json::{Map, Value};
+
+// Parses the arguments of a tool call. Models regularly send almost-json, so
+// anything that doesn't parse as is gets a second try after fixing the usual
+// mistakes.
+pub fn parse(arguments: &str) -> Result<Value, String> {
+    if let Ok(value) = serde_json::from_str(arguments) {
+        return Ok(value);
+    }
+
+    let repaired = repair(arguments);
+    serde_json::from_str(&repaired).map_err(|why| format!("Arguments are not valid json: {why}"))
+}
+
+// Strips code fences, turns single quoted strings into double quoted ones and
+// drops trailing commas. An empty argument string is treated as no arguments.
+fn repair(arguments: &str) -> String {
+    let mut arguments = arguments.trim();
+    if let Some(fenced) = arguments.strip_prefix("```") {
+        // The fence may be followed by a language such as json.
+        let fenced = fenced.trim_start_matches(|c: char| c.is_ascii_alphanumeric());
+        arguments = fenced.trim_end().trim_end_matches("```").trim();
+    }
+    if arguments.is_empty() {
+        return "{}".to_string();
+    }
+
+    let quoted = double_quote(arguments);
+    remove_trailing_commas(&quoted)
+}
+
+fn double_quote(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in text.chars() {
+        match quote {
+            None => {
+                if c == '\'' || c == '"' {
+                    quote = Some(c);
+                    output.push('"');
+                } else {
+                    output.push(c);
+                }
+            }
+            Some(current) => {
+                if escaped {
+                    escaped = false;
+                    // \' isn't a valid escape in json.
+                    if c != '\'' {
+                        output.push('\\');
+                    }
+                    output.push(c);
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == current {
+                    quote = None;
+                    output.push('"');
+                } else if c == '"' {
+                    // Only possible inside a single quoted string.
+                    output.push_str("\\\"");
+                } else {
+                    output.push(c);
+                }
+            }
+        }
+    }
+
+    output
+}
+
+fn remove_trailing_commas(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut output = String::with_capacity(text.len());
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (index, &c) in chars.iter().enumerate() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let next = chars[index + 1..].iter().find(|c| !c.is_whitespace());
+            if matches!(next, Some('}') | Some(']') | None) {
+                continue;
+            }
+        }
+        output.push(c);
+    }
+
+    output
+}
+
+// Checks arguments against the subset of json schema that argument structs
+// produce. Returns a description of the first problem found.
+pub fn validate(value: &Value, schema: &Value) -> Result<(), String> {
+    validate_at("arguments", value, schema)
+}
+
+fn validate_at(path: &str, value: &Value, schema: &Value) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        return Ok(());
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(kind) => vec![kind.as_str()],
+            Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|kind| has_type(value, kind)) {
+            return Err(format!(
+                "{path} should be {} but was {}",
+                types.join(" or "),
+                type_name(value)
+            ));
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            return Err(format!(
+                "{path} should be one of {}",
+                Value::Array(options.clone())
+            ));
+        }
+    }
+
+    if let Value::Object(fields) = value {
+        validate_fields(path, fields, schema)?;
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate_at(&format!("{path}[{index}]"), item, item_schema)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_fields(
+    path: &str,
+    fields: &Map<String, Value>,
+    schema: &Map<String, Value>,
+) -> Result<(), String> {
+    let empty = Map::new();
+    let properties = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+
+    for required in schema
+        .get("required")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+    {
+        if !fields.contains_key(required) {
+            return Err(format!("{path} is missing {required}"));
+        }
+    }
+
+    for (name, field) in fields {
+        match properties.get(name) {
+            Some(field_schema) => validate_at(&format!("{path}.{name}"), field, field_schema)?,
+            None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                return Err(format!("{path} has an unexpected field {name}"));
+            }
+            None => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn strips_code_fences_with_a_language() {
+        let arguments = "```json\n{\"emoji\": \"👍\"}\n```";
+        assert_eq!(parse(arguments), Ok(json!({ "emoji": "👍" })));
+        assert_eq!(parse("```{\"a\": 1}```"), Ok(json!({ "a": 1 })));
+    }
+
+    #[test]
+    fn turns_single_quotes_into_double_quotes() {
+        let arguments = r#"{'fact': 'Kay\'s cat is called "Mo"'}"#;
+        assert_eq!(
+            parse(arguments),
+            Ok(json!({ "fact": "Kay's cat is called \"Mo\"" }))
+        );
+    }
+
+    #[test]
+    fn removes_trailing_commas_but_not_inside_strings() {
+        let arguments = r#"{"names": ["a, ]", "b,}",], "fact": "x,",}"#;
+        assert_eq!(
+            parse(arguments),
+            Ok(json!({ "names": ["a, ]", "b,}"], "fact": "x," }))
+        );
+    }
+
+    #[test]
+    fn empty_arguments_are_no_arguments() {
+        assert_eq!(parse(""), Ok(json!({})));
+        assert_eq!(parse("  \n"), Ok(json!({})));
+        assert_eq!(parse("```json\n```"), Ok(json!({})));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_repair() {
+        assert!(parse("{fact: remember this}").is_err());
+    }
+
+    #[test]
+    fn reports_missing_required_fields() {
+        let schema = json!({
+            "type": "object",
+            "required": ["fact"],
+            "properties": { "fact": { "type": "string" } }
+        });
+        assert_eq!(
+            validate(&json!({}), &schema),
+            Err("arguments is missing fact".to_string())
+        );
+        assert_eq!(
+            validate(&json!({ "fact": 1 }), &schema),
+            Err("arguments.fact should be string but was a number".to_string())
+        );
+        assert_eq!(validate(&json!({ "fact": "x" }), &schema), Ok(()));
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::async_trait;
+
+use super::{NoArguments, Tool, ToolContext, ToolOutcome};
+use crate::conversation;
+
+pub struct React;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ReactArguments {
+    /// The emoji to react with.
+    emoji: String,
+}
+
+#[async_trait]
+impl Tool for React {
+    type Arguments = ReactArguments;
+
+    const NAME: &'static str = "react";
+    const DESCRIPTION: &'static str =
+        "Takes a string with a single emoji and reacts to the last message in the transcript with it.";
+
+    async fn run(
+        &self,
+        context: &ToolContext<'_>,
+        arguments: ReactArguments,
+    ) -> Result<ToolOutcome> {
+        let reaction = arguments.emoji;
+        dbg!(&reaction);
+
+        // Response might not be a valid emoji
+        let reacted = match reaction.chars().next() {
+            Some(possible_emoji) => context
+                .message
+                .react(&context.ctx.http, possible_emoji)
+                .await
+                .is_ok(),
+            None => false,
+        };
+        if reacted {
+            Ok(ToolOutcome::done("Reacted."))
+        } else {
+            Ok(ToolOutcome::done(format!("{reaction} is not an emoji.")))
+        }
+    }
+}
+
+pub struct Pin;
+
+#[async_trait]
+impl Tool for Pin {
+    type Arguments = NoArguments;
+
+    const NAME: &'static str = "pin";
+    const DESCRIPTION: &'static str =
+        "Pins the message the last message was replying to. If that fails, a reason why is returned.";
+
+    async fn run(&self, context: &ToolContext<'_>, _: NoArguments) -> Result<ToolOutcome> {
+        let Some(referenced_message) = context.message.referenced_message.as_ref() else {
+            dbg!("pin failed");
+            return Ok(ToolOutcome::follow_up("Last message was not a reply."));
+        };
+
+        dbg!("pinned message");
+        Ok(match referenced_message.pin(&context.ctx.http).await {
+            Ok(()) => ToolOutcome::follow_up("Pinned the message."),
+            Err(why) => ToolOutcome::follow_up(format!("Could not pin: {why}")),
+        })
+    }
+}
+
+pub struct Unpin;
+
+#[async_trait]
+impl Tool for Unpin {
+    type Arguments = NoArguments;
+
+    const NAME: &'static str = "unpin";
+    const DESCRIPTION: &'static str =
+        "Unpins the message the last message was replying to. If that fails, a reason why is returned.";
+
+    async fn run(&self, context: &ToolContext<'_>, _: NoArguments) -> Result<ToolOutcome> {
+        let Some(referenced_message) = context.message.referenced_message.as_ref() else {
+            dbg!("unpin failed");
+            return Ok(ToolOutcome::follow_up("Last message was not a reply."));
+        };
+
+        dbg!("unpinned message");
+        Ok(match referenced_message.unpin(&context.ctx.http).await {
+            Ok(()) => ToolOutcome::follow_up("Unpinned the message."),
+            Err(why) => ToolOutcome::follow_up(format!("Could not unpin: {why}")),
+        })
+    }
+}
+
+pub struct StopListening;
+
+#[async_trait]
+impl Tool for StopListening {
+    type Arguments = NoArguments;
+
+    const NAME: &'static str = "stop_listening";
+    const DESCRIPTION: &'static str =
+        "Stops listening to the chat. Should be called when the last message isn't directed at Astro.";
+
+    async fn run(&self, context: &ToolContext<'_>, _: NoArguments) -> Result<ToolOutcome> {
+        dbg!("stopped listening");
+        conversation::stop(context.message.channel_id.0)?;
+        Ok(ToolOutcome::done("Stopped listening."))
+    }
+}
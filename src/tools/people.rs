@@ -0,0 +1,87 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::async_trait;
+
+use super::{NoArguments, Tool, ToolContext, ToolOutcome};
+use crate::{ai, config::config, users};
+
+pub struct GetUsers;
+
+#[async_trait]
+impl Tool for GetUsers {
+    type Arguments = NoArguments;
+
+    const NAME: &'static str = "get_users";
+    const DESCRIPTION: &'static str = "Gets the users in the chat. Returns a list of users.";
+
+    async fn run(&self, context: &ToolContext<'_>, _: NoArguments) -> Result<ToolOutcome> {
+        let names = users::display_names(context.ctx, context.message.guild_id).await?;
+        Ok(ToolOutcome::follow_up(format!("[{}]", names.join(", "))))
+    }
+}
+
+pub struct Angry;
+
+#[async_trait]
+impl Tool for Angry {
+    type Arguments = NoArguments;
+
+    const NAME: &'static str = "angry";
+    const DESCRIPTION: &'static str =
+        "Marks that the author of the most recent message has been rude or mean.";
+
+    async fn run(&self, context: &ToolContext<'_>, _: NoArguments) -> Result<ToolOutcome> {
+        let opinion = ai::decrement_user_opinion(context.message.author.id.0)?;
+        Ok(ToolOutcome::follow_up(format!(
+            "Opinion of the author is now {opinion}."
+        )))
+    }
+}
+
+pub struct Happy;
+
+#[async_trait]
+impl Tool for Happy {
+    type Arguments = NoArguments;
+
+    const NAME: &'static str = "happy";
+    const DESCRIPTION: &'static str =
+        "Marks that the author of the most recent message has been kind or nice.";
+
+    async fn run(&self, context: &ToolContext<'_>, _: NoArguments) -> Result<ToolOutcome> {
+        let opinion = ai::increment_user_opinion(context.message.author.id.0)?;
+        Ok(ToolOutcome::follow_up(format!(
+            "Opinion of the author is now {opinion}."
+        )))
+    }
+}
+
+pub struct GetOpinion;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GetOpinionArguments {
+    /// The name of the user to get the opinion of.
+    name: String,
+}
+
+#[async_trait]
+impl Tool for GetOpinion {
+    type Arguments = GetOpinionArguments;
+
+    const NAME: &'static str = "get_opinion";
+    const DESCRIPTION: &'static str = "Gets the opinion of a user by name. Returns a number from 0 to 100 with 0 meaning that the user is very rude and 100 meaning they are very kind.";
+
+    async fn run(
+        &self,
+        context: &ToolContext<'_>,
+        arguments: GetOpinionArguments,
+    ) -> Result<ToolOutcome> {
+        let opinion =
+            match users::find(context.ctx, context.message.guild_id, &arguments.name).await? {
+                Some(user_id) => ai::user_opinion(user_id)?,
+                None => config().opinions.default,
+            };
+        Ok(ToolOutcome::follow_up(opinion.to_string()))
+    }
+}
@@ -0,0 +1,40 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serenity::async_trait;
+
+use super::{Tool, ToolContext, ToolOutcome};
+use crate::memory;
+
+pub struct Remember;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RememberArguments {
+    /// The fact to remember, written so that it makes sense on its own.
+    fact: String,
+}
+
+#[async_trait]
+impl Tool for Remember {
+    type Arguments = RememberArguments;
+
+    const NAME: &'static str = "remember";
+    const DESCRIPTION: &'static str = "Stores a fact to remember long term, such as something a user likes or something that happened to them.";
+
+    async fn run(
+        &self,
+        context: &ToolContext<'_>,
+        arguments: RememberArguments,
+    ) -> Result<ToolOutcome> {
+        // Facts stay in the server they were learned in, or in the direct
+        // messages.
+        let message = context.message;
+        let scope = message
+            .guild_id
+            .map_or(message.channel_id.0, |guild_id| guild_id.0);
+        Ok(match memory::remember_fact(scope, &arguments.fact).await {
+            Ok(()) => ToolOutcome::follow_up("Remembered."),
+            Err(why) => ToolOutcome::follow_up(format!("Could not remember that: {why}")),
+        })
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use crate::{
+    config::{config, QuotaAction, UsageConfig},
+    provider::{ChatRequest, ChatResponse, Usage},
+    storage::{storage, UsageRecord},
+    tokens,
+};
+
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+// What a user may get before Astro answers them.
+pub enum Quota {
+    Allowed,
+    Downgrade(String),
+    Declined,
+}
+
+// Converts days since the unix epoch into a UTC (year, month, day), using
+// Howard Hinnant's civil_from_days.
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+fn today() -> (i64, u32, u32) {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    civil_date((seconds / SECONDS_PER_DAY) as i64)
+}
+
+fn day_string((year, month, day): (i64, u32, u32)) -> String {
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+// Providers which don't report usage get an estimate instead.
+fn estimate(request: &ChatRequest, response: &ChatResponse) -> Usage {
+    let prompt_tokens = request
+        .messages
+        .iter()
+        .map(tokens::message_tokens)
+        .sum::<usize>()
+        + tokens::tool_tokens(&request.tools);
+    Usage {
+        prompt_tokens: prompt_tokens as u32,
+        completion_tokens: tokens::message_tokens(&response.message) as u32,
+    }
+}
+
+// Records what a completion cost. Completions which aren't on behalf of a
+// user, like summaries, pass None.
+pub fn record(
+    user_id: Option<u64>,
+    channel_id: u64,
+    request: &ChatRequest,
+    response: &ChatResponse,
+) -> Result<()> {
+    let usage = response
+        .usage
+        .unwrap_or_else(|| estimate(request, response));
+    let cost = config()
+        .price(&response.model)
+        .map(|price| {
+            (usage.prompt_tokens as f64 * price.prompt
+                + usage.completion_tokens as f64 * price.completion)
+                / 1000.0
+        })
+        .unwrap_or_default();
+
+    storage().record_usage(&UsageRecord {
+        day: day_string(today()),
+        user_id: user_id.unwrap_or_default(),
+        channel_id,
+        model: response.model.clone(),
+        prompt_tokens: usage.prompt_tokens as u64,
+        completion_tokens: usage.completion_tokens as u64,
+        cost,
+    })
+}
+
+fn over(limit: Option<f64>, spent: f64) -> bool {
+    limit.map(|limit| spent >= limit).unwrap_or_default()
+}
+
+// Checks the spending limits for a message from the user in the channel.
+pub fn check(user_id: u64, channel_id: u64) -> Result<Quota> {
+    let (year, month, day) = today();
+    let records = storage().usage_since(&day_string((year, month, 1)))?;
+    Ok(quota(
+        &records,
+        user_id,
+        channel_id,
+        &day_string((year, month, day)),
+        &config().usage,
+    ))
+}
+
+// The quota given this month's records, with today as YYYY-MM-DD.
+fn quota(
+    records: &[UsageRecord],
+    user_id: u64,
+    channel_id: u64,
+    today: &str,
+    limits: &UsageConfig,
+) -> Quota {
+    let spent = |include: &dyn Fn(&UsageRecord) -> bool| -> f64 {
+        records
+            .iter()
+            .filter(|record| include(record))
+            .map(|record| record.cost)
+            .sum()
+    };
+
+    if over(limits.monthly_limit, spent(&|_| true)) {
+        return Quota::Declined;
+    }
+
+    let over_quota = over(
+        limits.daily_user_limit,
+        spent(&|record| record.user_id == user_id && record.day == today),
+    ) || over(
+        limits.monthly_user_limit,
+        spent(&|record| record.user_id == user_id),
+    ) || over(
+        limits.daily_channel_limit,
+        spent(&|record| record.channel_id == channel_id && record.day == today),
+    ) || over(
+        limits.monthly_channel_limit,
+        spent(&|record| record.channel_id == channel_id),
+    );
+
+    match (over_quota, limits.over_quota, &limits.downgrade_model) {
+        (false, _, _) => Quota::Allowed,
+        (true, QuotaAction::Downgrade, Some(model)) => Quota::Downgrade(model.clone()),
+        (true, _, _) => Quota::Declined,
+    }
+}
+
+fn totals<'a>(records: impl Iterator<Item = &'a UsageRecord>) -> (f64, u64) {
+    records.fold((0.0, 0), |(cost, tokens), record| {
+        (
+            cost + record.cost,
+            tokens + record.prompt_tokens + record.completion_tokens,
+        )
+    })
+}
+
+fn breakdown<K: Eq + std::hash::Hash>(
+    records: &[UsageRecord],
+    key: impl Fn(&UsageRecord) -> K,
+) -> Vec<(K, f64)> {
+    let mut costs: HashMap<K, f64> = HashMap::new();
+    for record in records {
+        *costs.entry(key(record)).or_default() += record.cost;
+    }
+    costs
+        .into_iter()
+        .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+        .collect()
+}
+
+// Spend for today and this month, broken down by user, channel and model.
+// Users are shown with the given names where there is one.
+pub fn report(names: &HashMap<u64, String>) -> Result<String> {
+    let (year, month, day) = today();
+    let today = day_string((year, month, day));
+    let records = storage().usage_since(&day_string((year, month, 1)))?;
+
+    let (today_cost, today_tokens) = totals(records.iter().filter(|record| record.day == today));
+    let (month_cost, month_tokens) = totals(records.iter());
+
+    let mut lines = vec![
+        format!("Today: ${today_cost:.2} ({today_tokens} tokens)"),
+        format!("This month: ${month_cost:.2} ({month_tokens} tokens)"),
+    ];
+    if let Some(limit) = config().usage.monthly_limit {
+        lines.push(format!(
+            "Monthly budget: ${limit:.2}, ${:.2} left",
+            (limit - month_cost).max(0.0)
+        ));
+    }
+
+    lines.push("\nTop users this month:".to_string());
+    for (user_id, cost) in breakdown(&records, |record| record.user_id)
+        .into_iter()
+        .take(10)
+    {
+        let name = match user_id {
+            0 => "(upkeep)".to_string(),
+            user_id => names
+                .get(&user_id)
+                .cloned()
+                .unwrap_or_else(|| user_id.to_string()),
+        };
+        lines.push(format!("- {name}: ${cost:.2}"));
+    }
+
+    lines.push("\nChannels this month:".to_string());
+    for (channel_id, cost) in breakdown(&records, |record| record.channel_id) {
+        lines.push(format!("- <#{channel_id}>: ${cost:.2}"));
+    }
+
+    lines.push("\nModels this month:".to_string());
+    for (model, cost) in breakdown(&records, |record| record.model.clone()) {
+        lines.push(format!("- {model}: ${cost:.2}"));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TODAY: &str = "2024-03-15";
+
+    fn record(day: &str, user_id: u64, channel_id: u64, cost: f64) -> UsageRecord {
+        UsageRecord {
+            day: day.to_string(),
+            user_id,
+            channel_id,
+            model: "gpt-4".to_string(),
+            prompt_tokens: 100,
+            completion_tokens: 100,
+            cost,
+        }
+    }
+
+    fn allowed(records: &[UsageRecord], limits: &UsageConfig) -> bool {
+        matches!(quota(records, 1, 10, TODAY, limits), Quota::Allowed)
+    }
+
+    #[test]
+    fn civil_dates_roll_over() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(10956), (1999, 12, 31));
+        assert_eq!(civil_date(10957), (2000, 1, 1));
+        assert_eq!(civil_date(11016), (2000, 2, 29));
+        assert_eq!(civil_date(11017), (2000, 3, 1));
+        assert_eq!(civil_date(47540), (2100, 2, 28));
+        assert_eq!(civil_date(47541), (2100, 3, 1));
+        assert_eq!(day_string(civil_date(20089)), "2025-01-01");
+    }
+
+    #[test]
+    fn daily_limits_only_count_today() {
+        let limits = UsageConfig {
+            daily_user_limit: Some(1.0),
+            ..Default::default()
+        };
+        let earlier = [record("2024-03-14", 1, 10, 5.0)];
+        assert!(allowed(&earlier, &limits));
+        let today = [record(TODAY, 1, 10, 0.5), record(TODAY, 1, 11, 0.5)];
+        assert!(!allowed(&today, &limits));
+        let someone_else = [record(TODAY, 2, 10, 5.0)];
+        assert!(allowed(&someone_else, &limits));
+    }
+
+    #[test]
+    fn monthly_limits_count_the_whole_month() {
+        let limits = UsageConfig {
+            monthly_channel_limit: Some(1.0),
+            ..Default::default()
+        };
+        let records = [
+            record("2024-03-01", 2, 10, 0.5),
+            record("2024-03-14", 3, 10, 0.5),
+        ];
+        assert!(!allowed(&records, &limits));
+        let other_channel = [record("2024-03-01", 1, 11, 5.0)];
+        assert!(allowed(&other_channel, &limits));
+    }
+
+    #[test]
+    fn over_quota_downgrades_when_configured() {
+        let limits = UsageConfig {
+            monthly_user_limit: Some(1.0),
+            over_quota: QuotaAction::Downgrade,
+            downgrade_model: Some("gpt-3.5-turbo".to_string()),
+            ..Default::default()
+        };
+        let records = [record("2024-03-01", 1, 10, 2.0)];
+        assert!(matches!(
+            quota(&records, 1, 10, TODAY, &limits),
+            Quota::Downgrade(model) if model == "gpt-3.5-turbo"
+        ));
+    }
+
+    #[test]
+    fn the_monthly_budget_declines_everyone() {
+        let limits = UsageConfig {
+            monthly_limit: Some(1.0),
+            over_quota: QuotaAction::Downgrade,
+            downgrade_model: Some("gpt-3.5-turbo".to_string()),
+            ..Default::default()
+        };
+        let records = [record("2024-03-01", 2, 11, 1.0)];
+        assert!(matches!(
+            quota(&records, 1, 10, TODAY, &limits),
+            Quota::Declined
+        ));
+        assert!(allowed(&records[..0], &limits));
+    }
+}
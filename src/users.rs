@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use serenity::{
+    model::prelude::{GuildId, Member, User, UserId},
+    prelude::Context,
+};
+
+use crate::{config::config, storage::read_json};
+
+const ALIASES_PATH: &str = "aliases.json";
+// Member lists which aren't kept up to date by member events, or which
+// couldn't be fetched, are fetched again once they are this old.
+const MEMBERS_TTL: Duration = Duration::from_secs(10 * 60);
+
+struct MemberNames {
+    display_name: String,
+    username: String,
+}
+
+struct GuildMembers {
+    fetched: Instant,
+    complete: bool,
+    members: HashMap<u64, MemberNames>,
+}
+
+lazy_static! {
+    // Extra names for people who go by something other than their nickname.
+    // Also the only names available in direct messages.
+    static ref ALIASES: HashMap<String, u64> =
+        read_json(Path::new(ALIASES_PATH)).expect("Could not load aliases");
+    // Members of each guild Astro has seen, keyed by guild id. Filled on first
+    // use and kept up to date by the member events when they are enabled.
+    static ref MEMBERS: RwLock<HashMap<u64, GuildMembers>> = RwLock::new(HashMap::new());
+}
+
+pub fn init() {
+    lazy_static::initialize(&ALIASES);
+}
+
+async fn fetch_members(ctx: &Context, guild_id: GuildId) -> Result<HashMap<u64, MemberNames>> {
+    let mut members = HashMap::new();
+    let mut after = None;
+    loop {
+        let page = guild_id.members(&ctx.http, Some(1000), after).await?;
+        after = page.last().map(|member| member.user.id);
+        let done = page.len() < 1000;
+
+        for member in page.into_iter().filter(|member| !member.user.bot) {
+            members.insert(member.user.id.0, names(member.nick.as_ref(), &member.user));
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    Ok(members)
+}
+
+// Listing members needs the server members intent to be enabled in the
+// developer portal. Without it there are only the aliases to go by, which is
+// better than not answering at all.
+async fn ensure_loaded(ctx: &Context, guild_id: GuildId) {
+    let fresh = MEMBERS
+        .read()
+        .unwrap()
+        .get(&guild_id.0)
+        .map_or(false, |guild| {
+            let kept_up_to_date = guild.complete && config().members.events;
+            kept_up_to_date || guild.fetched.elapsed() < MEMBERS_TTL
+        });
+    if fresh {
+        return;
+    }
+
+    let (complete, members) = match fetch_members(ctx, guild_id).await {
+        Ok(members) => (true, members),
+        Err(why) => {
+            println!("Error fetching members of {}: {:?}", guild_id.0, why);
+            (false, HashMap::new())
+        }
+    };
+    MEMBERS.write().unwrap().insert(
+        guild_id.0,
+        GuildMembers {
+            fetched: Instant::now(),
+            complete,
+            members,
+        },
+    );
+}
+
+fn names(nick: Option<&String>, user: &User) -> MemberNames {
+    MemberNames {
+        display_name: nick.cloned().unwrap_or_else(|| user.name.clone()),
+        username: user.name.clone(),
+    }
+}
+
+pub fn update_member(guild_id: GuildId, nick: Option<&String>, user: &User) {
+    if user.bot {
+        return;
+    }
+
+    // Guilds which haven't been loaded yet get everything on first use.
+    if let Some(guild) = MEMBERS.write().unwrap().get_mut(&guild_id.0) {
+        guild.members.insert(user.id.0, names(nick, user));
+    }
+}
+
+pub fn add_member(member: &Member) {
+    update_member(member.guild_id, member.nick.as_ref(), &member.user);
+}
+
+pub fn remove_member(guild_id: GuildId, user_id: UserId) {
+    if let Some(guild) = MEMBERS.write().unwrap().get_mut(&guild_id.0) {
+        guild.members.remove(&user_id.0);
+    }
+}
+
+// Every name a user can be referred to by, including usernames and aliases.
+// Longer names come first so that "@Kayla" is matched before "@Kay".
+pub async fn all_names(ctx: &Context, guild_id: Option<GuildId>) -> Result<Vec<(String, u64)>> {
+    let mut names: Vec<(String, u64)> = ALIASES
+        .iter()
+        .map(|(name, id)| (name.clone(), *id))
+        .collect();
+
+    if let Some(guild_id) = guild_id {
+        ensure_loaded(ctx, guild_id).await;
+        if let Some(guild) = MEMBERS.read().unwrap().get(&guild_id.0) {
+            for (id, member) in guild.members.iter() {
+                names.push((member.display_name.clone(), *id));
+                names.push((member.username.clone(), *id));
+            }
+        }
+    }
+
+    Ok(names
+        .into_iter()
+        .unique()
+        .sorted_by_key(|(name, _)| std::cmp::Reverse(name.len()))
+        .collect())
+}
+
+// The name to show for each user, keyed by user id.
+pub async fn names_by_id(ctx: &Context, guild_id: Option<GuildId>) -> Result<HashMap<u64, String>> {
+    let mut names: HashMap<u64, String> = ALIASES
+        .iter()
+        .map(|(name, id)| (*id, name.clone()))
+        .collect();
+
+    if let Some(guild_id) = guild_id {
+        ensure_loaded(ctx, guild_id).await;
+        if let Some(guild) = MEMBERS.read().unwrap().get(&guild_id.0) {
+            for (id, member) in guild.members.iter() {
+                names
+                    .entry(*id)
+                    .or_insert_with(|| member.display_name.clone());
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+// The names to show the model, one per user.
+pub async fn display_names(ctx: &Context, guild_id: Option<GuildId>) -> Result<Vec<String>> {
+    Ok(names_by_id(ctx, guild_id)
+        .await?
+        .into_values()
+        .sorted()
+        .collect())
+}
+
+pub async fn find(ctx: &Context, guild_id: Option<GuildId>, name: &str) -> Result<Option<u64>> {
+    let name = name.trim_start_matches('@').to_lowercase();
+    Ok(all_names(ctx, guild_id)
+        .await?
+        .into_iter()
+        .find(|(candidate, _)| candidate.to_lowercase() == name)
+        .map(|(_, id)| id))
+}